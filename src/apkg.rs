@@ -1,47 +1,98 @@
 use crate::deck;
+use crate::media;
 use serde::{Deserialize, Serialize};
 use std::{
-    fs, io,
-    io::Write,
-    path::{Path, PathBuf},
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fmt, fs, io,
+    io::{Read, Seek},
+    path::{Component, Path, PathBuf},
 };
 use tempfile;
+use unicode_normalization::UnicodeNormalization;
 use zip;
 
-// Owns the temporary extracted Apkg and the collection
+// Anything an apkg can be read from
+pub trait ReadSeek: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+// The zip the apkg was loaded from, media is read from it when needed
+struct Archive(RefCell<zip::ZipArchive<Box<dyn ReadSeek>>>);
+
+impl fmt::Debug for Archive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Archive({} entries)", self.0.borrow().len())
+    }
+}
+
+// Owns the source archive, the extracted collection and the collection
+// Media stays in the archive until it is added or changed, then it lives in the temporary
+// directory next to the collection
 #[derive(Debug)]
 pub struct Apkg {
     dir: tempfile::TempDir,
     db_path: PathBuf,
-    media_path: PathBuf,
+    archive: Archive,
     collection: deck::Collection,
     media: Vec<Media>,
+    extra_entries: Vec<String>, // Archive entries that are neither collection nor media
+    prune_media: bool,          // Remove unused media when saving
 }
 
 // Media files in the apkg
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Media {
-    path: PathBuf,
+    entry: String,
+    path: Option<PathBuf>, // Set once the contents differ from the archive
     name: String,
 }
 
-fn load_media(path: &Path) -> io::Result<Vec<Media>> {
+impl Media {
+    // Name of the file as notes refer to it
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Numeric name of the entry in the archive
+    pub fn entry(&self) -> &str {
+        &self.entry
+    }
+}
+
+fn media_not_found(name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no media named {}", name))
+}
+
+fn zip_error(err: zip::result::ZipError) -> io::Error {
+    match err {
+        zip::result::ZipError::Io(err) => err,
+        err => io::Error::other(err),
+    }
+}
+
+// Parse the "media" JSON, a map of archive entry to filename
+fn load_media(contents: &str) -> io::Result<Vec<Media>> {
     let mut vec = Vec::new();
 
-    let contents = fs::read_to_string(path)?;
-    let json = json::parse(&contents).expect("Media JSON is not JSON");
+    let json =
+        json::parse(contents).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
     if !json.is_object() {
         return Ok(vec);
     }
 
-    let dir = path.parent().unwrap();
     for (condensed_name, value) in json.entries() {
+        // Anki only writes numbered entries, anything else could name a path outside the
+        // archive or the collection itself
+        if condensed_name.is_empty() || !condensed_name.bytes().all(|b| b.is_ascii_digit()) {
+            continue;
+        }
         if let Some(val) = value.as_str() {
-            let name = String::from(val);
-            let mediapath = dir.join(condensed_name);
             vec.push(Media {
-                path: mediapath,
-                name,
+                entry: String::from(condensed_name),
+                path: None,
+                name: String::from(val),
             });
         }
     }
@@ -49,126 +100,444 @@ fn load_media(path: &Path) -> io::Result<Vec<Media>> {
     Ok(vec)
 }
 
-// Path is path to "media", v is the entries in the JSON
-fn save_media(path: &Path, v: Vec<Media>) -> io::Result<()> {
-    fs::remove_file(path)?;
+// Make a rename in dir durable
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+// Numeric entries in numeric order, then anything else by name
+fn entry_order(a: &str, b: &str) -> Ordering {
+    match (a.parse::<u64>(), b.parse::<u64>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => a.cmp(b),
+    }
+}
+
+fn media_json(v: &[Media]) -> String {
     let mut json = object! {};
 
-    for media in v.into_iter() {
-        let name = media.path.file_name().unwrap();
-        json.insert(name.to_str().unwrap(), media.name).unwrap();
+    for media in v.iter() {
+        json.insert(&media.entry, media.name.clone()).unwrap();
     }
 
-    let json_text = json::stringify(json);
+    json::stringify(json)
+}
 
-    let mut file = fs::File::create(path)?;
-    file.write_all(json_text.as_bytes())
+// How entries are compressed when saving
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Stored,
+    Deflate(Option<i32>), // Level 0-9, or the library default
+    Zstd(Option<i32>),    // Level 1-22, or the library default
+}
+
+impl Compression {
+    fn method(&self) -> zip::CompressionMethod {
+        match self {
+            Compression::Stored => zip::CompressionMethod::Stored,
+            Compression::Deflate(_) => zip::CompressionMethod::Deflated,
+            Compression::Zstd(_) => zip::CompressionMethod::Zstd,
+        }
+    }
+
+    fn level(&self) -> Option<i32> {
+        match self {
+            Compression::Stored => None,
+            Compression::Deflate(level) => *level,
+            Compression::Zstd(level) => *level,
+        }
+    }
+}
+
+// Options for writing a package
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SaveOptions {
+    pub compression: Compression,
+    // Sort entries and fix timestamps and permissions so the same content always gives
+    // the same bytes
+    pub deterministic: bool,
+    pub backup: bool, // Keep the previous file as <name>.bak
+}
+
+impl Default for SaveOptions {
+    fn default() -> Self {
+        SaveOptions {
+            compression: Compression::Stored,
+            deterministic: false,
+            backup: false,
+        }
+    }
+}
+
+impl SaveOptions {
+    fn file_options(&self) -> zip::write::FileOptions {
+        let options = zip::write::FileOptions::default()
+            .compression_method(self.compression.method())
+            .compression_level(self.compression.level());
+        if self.deterministic {
+            options
+                .last_modified_time(zip::DateTime::default())
+                .unix_permissions(0o644)
+        } else {
+            options
+        }
+    }
+
+    // Whether an entry compressed with method can be copied without recompressing it
+    // Copies keep their original timestamps and levels, so only plain saves use them
+    fn can_copy(&self, method: zip::CompressionMethod) -> bool {
+        !self.deterministic
+            && self.compression.level().is_none()
+            && self.compression.method() == method
+    }
 }
 
 impl Apkg {
-    // Extract an apkg into a temporary directory which is owned by the resulting struct
+    // Open an apkg file
     pub fn new(path: &Path) -> io::Result<Self> {
-        // Open the zip archive
         let file = fs::File::open(path)?;
-        let mut archive = zip::ZipArchive::new(file)?;
+        Apkg::from_reader(io::BufReader::new(file))
+    }
+
+    // Load an apkg from something seekable, such as a file or an in-memory buffer
+    // Only the collection is extracted, media is read from the reader when needed
+    pub fn from_reader<R: Read + Seek + Send + 'static>(reader: R) -> io::Result<Self> {
+        let reader: Box<dyn ReadSeek> = Box::new(reader);
+        let mut archive = zip::ZipArchive::new(reader).map_err(zip_error)?;
         // Make a temporary directory that will be owned by the resultant Apkg
         let dir = tempfile::tempdir()?;
 
-        // Extract the contents of the zip file to the temporary directory
-        for i in 0..archive.len() {
-            // Get the path of the file
-            let mut file = archive.by_index(i)?;
-            let outpath = match file.enclosed_name() {
-                Some(path) => path.to_owned(),
-                None => continue,
-            };
-
-            let outpath = dir.path().join(outpath);
-
-            if (&*file.name()).ends_with("/") {
-                // File is a directory, create it in tempdir
-                fs::create_dir_all(&outpath)?;
-            } else {
-                // File is not a directory, extract it
-                if let Some(p) = outpath.parent() {
-                    // Create directory if needed
-                    if !p.exists() {
-                        fs::create_dir_all(&p)?;
-                    }
-                }
-                let mut outfile = fs::File::create(&outpath)?;
-                io::copy(&mut file, &mut outfile)?;
-            }
+        // Extract the collection, sqlite needs it on disk
+        let db_path = dir.path().join("collection.anki2");
+        {
+            let mut file = archive.by_name("collection.anki2").map_err(zip_error)?;
+            let mut outfile = fs::File::create(&db_path)?;
+            io::copy(&mut file, &mut outfile)?;
+        }
 
-            // Set permissions on unix
-            #[cfg(unix)]
-            {
-                use std::os::unix::fs::PermissionsExt;
-                if let Some(mode) = file.unix_mode() {
-                    fs::set_permissions(&outpath, fs::Permissions::from_mode(mode))?;
-                }
+        let media = match archive.by_name("media") {
+            Ok(mut file) => {
+                let mut contents = String::new();
+                file.read_to_string(&mut contents)?;
+                load_media(&contents)?
             }
-        }
+            Err(zip::result::ZipError::FileNotFound) => Vec::new(),
+            Err(err) => return Err(zip_error(err)),
+        };
 
-        let db_path = dir.path().join("collection.anki2");
-        let media_path = dir.path().join("media");
-        let collection = deck::Collection::new(db_path.as_path());
-        if let Err(err) = collection {
-            return Err(io::Error::new(io::ErrorKind::Other, err));
-        }
-        let collection = collection.unwrap();
+        let known: HashSet<&str> = media.iter().map(|m| m.entry.as_str()).collect();
+        let extra_entries = archive
+            .file_names()
+            .filter(|n| !matches!(*n, "collection.anki2" | "media"))
+            .filter(|n| !known.contains(n) && !n.ends_with('/'))
+            // Like ZipFile::enclosed_name, drop entries that would extract outside the directory
+            .filter(|n| {
+                Path::new(n)
+                    .components()
+                    .all(|c| matches!(c, Component::Normal(_)))
+            })
+            .map(String::from)
+            .collect();
 
-        let media = load_media(media_path.as_path())?;
+        let collection = deck::Collection::new(db_path.as_path()).map_err(io::Error::other)?;
 
         let apkg = Apkg {
             dir,
             db_path,
-            media_path,
+            archive: Archive(RefCell::new(archive)),
             collection,
             media,
+            extra_entries,
+            prune_media: false,
         };
 
         Ok(apkg)
     }
 
+    // Load an apkg from a reader that cannot seek, such as stdin
+    // The data is spooled to an anonymous temporary file rather than memory
+    pub fn from_stream<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut file = tempfile::tempfile()?;
+        io::copy(&mut reader, &mut file)?;
+        file.rewind()?;
+        Apkg::from_reader(io::BufReader::new(file))
+    }
+
+    pub fn collection(&self) -> &deck::Collection {
+        &self.collection
+    }
+
+    pub fn collection_mut(&mut self) -> &mut deck::Collection {
+        &mut self.collection
+    }
+
+    // Media files in the package
+    pub fn media(&self) -> &[Media] {
+        &self.media
+    }
+
+    // Index of a media file, by its name as given or normalised
+    fn find_media(&self, name: &str) -> Option<usize> {
+        self.media.iter().position(|m| m.name == name).or_else(|| {
+            // The package may store names that are not NFC
+            let normalized = media::normalize_filename(name);
+            self.media
+                .iter()
+                .position(|m| m.name == normalized || m.name.nfc().eq(normalized.chars()))
+        })
+    }
+
+    // Archive entry for a new media file, one past the highest in use
+    fn next_media_entry(&self) -> String {
+        let archive = self.archive.0.borrow();
+        let next = self
+            .media
+            .iter()
+            .map(|m| m.entry.as_str())
+            .chain(archive.file_names())
+            .filter_map(|entry| entry.parse::<u64>().ok())
+            .max()
+            .map(|n| n + 1)
+            .unwrap_or(0);
+        next.to_string()
+    }
+
+    // Write media contents to a new file in the temporary directory
+    fn write_media_file(&self, bytes: &[u8]) -> io::Result<PathBuf> {
+        let mut file = tempfile::Builder::new()
+            .prefix("media-")
+            .tempfile_in(self.dir.path())?;
+        io::Write::write_all(&mut file, bytes)?;
+        let path = file.into_temp_path().keep().map_err(|err| err.error)?;
+        Ok(path)
+    }
+
+    pub fn read_media(&self, name: &str) -> io::Result<Vec<u8>> {
+        let media = match self.find_media(name) {
+            Some(i) => &self.media[i],
+            None => return Err(media_not_found(name)),
+        };
+        if let Some(path) = &media.path {
+            return fs::read(path);
+        }
+
+        let mut archive = self.archive.0.borrow_mut();
+        let mut file = archive.by_name(&media.entry).map_err(zip_error)?;
+        // The size in the header is not trusted, let the buffer grow as data is read
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    // Add a media file, returning the name it was stored under
+    // Adding identical contents under an existing name reuses it, different contents
+    // get the hash of the new file appended to the name
+    pub fn add_media(&mut self, name: &str, bytes: &[u8]) -> io::Result<String> {
+        let mut name = media::normalize_filename(name);
+        if self.find_media(&name).is_some() {
+            let existing = self.read_media(&name)?;
+            if existing == bytes {
+                return Ok(name);
+            }
+
+            name = media::add_hash_suffix(&name, &media::sha1_hex(bytes));
+            if self.find_media(&name).is_some() {
+                return Ok(name);
+            }
+        }
+
+        let entry = self.next_media_entry();
+        let path = self.write_media_file(bytes)?;
+        self.media.push(Media {
+            entry,
+            path: Some(path),
+            name: name.clone(),
+        });
+        Ok(name)
+    }
+
+    // Overwrite the contents of a media file, keeping its name
+    pub fn replace_media(&mut self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        let i = self.find_media(name).ok_or_else(|| media_not_found(name))?;
+        let path = self.write_media_file(bytes)?;
+        if let Some(old) = self.media[i].path.replace(path) {
+            fs::remove_file(old)?;
+        }
+        Ok(())
+    }
+
+    // Remove a media file, returning false if there was none by that name
+    pub fn remove_media(&mut self, name: &str) -> io::Result<bool> {
+        let i = match self.find_media(name) {
+            Some(i) => i,
+            None => return Ok(false),
+        };
+        let removed = self.media.remove(i);
+        if let Some(path) = removed.path {
+            fs::remove_file(path)?;
+        }
+        Ok(true)
+    }
+
+    // Rename a media file and every reference to it, returning the normalised new name
+    pub fn rename_media(&mut self, old: &str, new: &str) -> io::Result<String> {
+        let i = self.find_media(old).ok_or_else(|| media_not_found(old))?;
+        let new = media::normalize_filename(new);
+        if new == self.media[i].name {
+            return Ok(new);
+        }
+        if self.find_media(&new).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("media named {} already exists", new),
+            ));
+        }
+
+        let old = std::mem::replace(&mut self.media[i].name, new.clone());
+        let mut renames = HashMap::new();
+        renames.insert(old, new.clone());
+        self.collection.rename_media_references(&renames);
+        Ok(new)
+    }
+
+    // Whether save should first remove media that nothing refers to
+    pub fn set_prune_unused_media(&mut self, prune: bool) {
+        self.prune_media = prune;
+    }
+
+    // Write the package with the default options
     pub fn save(self, path: &Path) -> io::Result<()> {
-        // Write to temporary directory
-        save_media(self.media_path.as_path(), self.media)?;
+        self.save_with_options(path, &SaveOptions::default())
+    }
+
+    // Write the package, copying unchanged entries straight from the source archive when
+    // their compression already matches
+    pub fn save_with_options(mut self, path: &Path, options: &SaveOptions) -> io::Result<()> {
+        if self.prune_media {
+            self.prune_unused_media()?;
+        }
+
+        // Write the collection to the temporary directory
         if let Err(err) = self.collection.save(self.db_path.as_path()) {
-            return Err(io::Error::new(io::ErrorKind::Other, err));
+            return Err(io::Error::other(err));
         }
 
-        // Zip the archive
-        let file = fs::File::create(path)?;
-        let mut zip = zip::ZipWriter::new(file);
-        let options =
-            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        if options.deterministic {
+            self.media.sort_by(|a, b| entry_order(&a.entry, &b.entry));
+            self.extra_entries.sort_by(|a, b| entry_order(a, b));
+        }
 
-        let dir_path = self.dir.path();
-        let paths = fs::read_dir(dir_path)?;
+        // Zip the archive into a temporary file next to the destination, so a failed save
+        // leaves the old file alone and the rename cannot cross filesystems
+        let name = path.file_name().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a file path", path.display()),
+            )
+        })?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut builder = tempfile::Builder::new();
+        let prefix = format!(".{}.", name.to_string_lossy());
+        builder.prefix(&prefix).suffix(".tmp");
+        #[cfg(unix)]
+        {
+            // Same mode File::create would give, before the umask
+            use std::os::unix::fs::PermissionsExt;
+            builder.permissions(fs::Permissions::from_mode(0o666));
+        }
+        let temp = builder.tempfile_in(dir)?;
+        if let Ok(metadata) = fs::metadata(path) {
+            temp.as_file().set_permissions(metadata.permissions())?;
+        }
 
-        for path in paths {
-            if let Err(err) = path {
-                return Err(io::Error::new(io::ErrorKind::Other, err));
-            }
-            let path = path.unwrap();
-            if let Err(err) =
-                zip.start_file(path.path().file_name().unwrap().to_str().unwrap(), options)
-            {
-                return Err(io::Error::new(io::ErrorKind::Other, err));
-            }
+        let mut zip = zip::ZipWriter::new(io::BufWriter::new(temp.as_file()));
+        let file_options = options.file_options();
+        // Entries over 4 GiB need Zip64 headers, which have to be chosen before writing
+        let sized = |size: u64| file_options.large_file(size >= u64::from(u32::MAX));
+
+        let db_size = fs::metadata(&self.db_path)?.len();
+        zip.start_file("collection.anki2", sized(db_size))
+            .map_err(zip_error)?;
+        io::copy(&mut fs::File::open(&self.db_path)?, &mut zip)?;
 
-            let contents = fs::read(path.path())?;
-            if let Err(err) = zip.write(&contents[..]) {
-                return Err(io::Error::new(io::ErrorKind::Other, err));
+        zip.start_file("media", file_options).map_err(zip_error)?;
+        io::Write::write_all(&mut zip, media_json(&self.media).as_bytes())?;
+
+        let mut archive = self.archive.0.borrow_mut();
+        let entries = self
+            .media
+            .iter()
+            .map(|m| (m.entry.as_str(), m.path.as_deref()))
+            .chain(self.extra_entries.iter().map(|e| (e.as_str(), None)));
+        for (entry, media_path) in entries {
+            match media_path {
+                Some(media_path) => {
+                    let size = fs::metadata(media_path)?.len();
+                    zip.start_file(entry, sized(size)).map_err(zip_error)?;
+                    io::copy(&mut fs::File::open(media_path)?, &mut zip)?;
+                }
+                None => {
+                    let mut file = archive.by_name(entry).map_err(zip_error)?;
+                    if options.can_copy(file.compression()) {
+                        zip.raw_copy_file(file).map_err(zip_error)?;
+                    } else {
+                        zip.start_file(entry, sized(file.size()))
+                            .map_err(zip_error)?;
+                        io::copy(&mut file, &mut zip)?;
+                    }
+                }
             }
         }
 
         // Finish
-        if let Err(err) = zip.finish() {
-            return Err(io::Error::new(io::ErrorKind::Other, err));
+        let mut file = zip.finish().map_err(zip_error)?;
+        io::Write::flush(&mut file)?;
+        drop(file);
+        drop(zip);
+        temp.as_file().sync_all()?;
+
+        // Let go of the source archive, it may be the file being replaced
+        drop(archive);
+        drop(self.archive);
+
+        if options.backup && path.exists() {
+            let mut backup = name.to_os_string();
+            backup.push(".bak");
+            let backup = path.with_file_name(backup);
+            match fs::remove_file(&backup) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => (),
+            }
+            if fs::hard_link(path, &backup).is_err() {
+                fs::copy(path, &backup)?;
+            }
         }
 
+        temp.persist(path).map_err(|err| err.error)?;
+        sync_dir(dir)?;
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apkg_can_move_between_threads() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Apkg>();
+    }
+}
@@ -0,0 +1,264 @@
+/* This file is part of acp.
+ * Copyright (c) 2021 Wyatt Campbell
+ *
+ * See repository LICENSE for information.
+ */
+
+use crate::deck::{CardId, CardQueue, CardType, Collection, Due, Interval, ReviewLog, RevlogId};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+// Smooth out the review workload by moving due dates within each card's fuzz window,
+// the range of days Anki could have picked when the card was last answered
+
+// Load weight of each day of the week, Monday first
+// 1.0 is a normal day, lower values are easy days, 0.0 avoids the day where possible
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct EasyDays(pub [f64; 7]);
+
+impl Default for EasyDays {
+    fn default() -> Self {
+        EasyDays([1.0; 7])
+    }
+}
+
+// A card whose due date would move, days relative to today
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct Move {
+    pub card: CardId,
+    pub from: i32,
+    pub to: i32,
+}
+
+// Due counts per day before and after balancing, days relative to today
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct BalancePlan {
+    pub before: BTreeMap<i32, u32>,
+    pub after: BTreeMap<i32, u32>,
+    pub moves: Vec<Move>,
+}
+
+// Days either side of an interval that Anki's fuzz could have chosen
+pub fn fuzz_range(interval: u32) -> (u32, u32) {
+    let interval = f64::from(interval);
+    if interval < 2.5 {
+        return (interval as u32, interval as u32);
+    }
+
+    let ranges = [(2.5, 7.0, 0.15), (7.0, 20.0, 0.1), (20.0, f64::MAX, 0.05)];
+    let delta = 1.0
+        + ranges
+            .iter()
+            .map(|(start, end, factor)| factor * (interval.min(*end) - start).max(0.0))
+            .sum::<f64>();
+
+    let lower = (interval - delta).round().max(2.0) as u32;
+    let upper = (interval + delta).round() as u32;
+    (lower, upper)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Candidate {
+    card: CardId,
+    due: i32,      // collection day
+    reviewed: i32, // collection day of the last review
+    interval: u32,
+}
+
+impl Collection {
+    // Weekday of a collection day in a time zone minutes east of UTC, 0 being Monday
+    // Days start at the local rollover hour, so the local date is the date at the start
+    fn weekday(&self, day: i32, utc_offset: i32) -> usize {
+        let start = self.crt() + i64::from(day) * 86400 + i64::from(utc_offset) * 60;
+        let epoch_day = start.div_euclid(86400);
+        // The epoch fell on a Thursday
+        (epoch_day + 3).rem_euclid(7) as usize
+    }
+
+    // Review cards among the given ones that are due after today
+    fn balance_candidates(&self, cards: &[CardId]) -> Vec<Candidate> {
+        let today = self.today();
+        // A card listed twice would otherwise be moved twice
+        let mut seen = HashSet::new();
+        let mut candidates: Vec<Candidate> = cards
+            .iter()
+            .filter(|id| seen.insert(**id))
+            .filter_map(|id| self.card(*id))
+            .filter(|c| c.queue() == CardQueue::Review && c.card_type() == CardType::Review)
+            .filter_map(|c| match (c.due(), c.interval()) {
+                (Due::Day(due), Interval::Days(interval)) if due > today => Some(Candidate {
+                    card: c.id(),
+                    due,
+                    reviewed: due - interval as i32,
+                    interval,
+                }),
+                _ => None,
+            })
+            .collect();
+        // Cards with the widest windows are the easiest to move, so place them last
+        candidates.sort_by_key(|c| (c.interval, c.due, c.card));
+        candidates
+    }
+
+    // Work out how balancing the given cards would change the daily due counts
+    // Easy days are in local time, utc_offset is the user's time zone in minutes east of UTC
+    pub fn balance_preview(
+        &self,
+        cards: &[CardId],
+        easy_days: EasyDays,
+        utc_offset: i32,
+    ) -> BalancePlan {
+        let today = self.today();
+        let mut load: BTreeMap<i32, u32> = BTreeMap::new();
+        for card in self.cards().iter() {
+            if card.queue() != CardQueue::Review {
+                continue;
+            }
+            if let Due::Day(due) = card.due() {
+                *load.entry(due.max(today)).or_default() += 1;
+            }
+        }
+
+        let relative = |load: &BTreeMap<i32, u32>| {
+            load.iter()
+                .filter(|(_, count)| **count > 0)
+                .map(|(day, count)| (day - today, *count))
+                .collect::<BTreeMap<i32, u32>>()
+        };
+        let before = relative(&load);
+
+        let mut moves = Vec::new();
+        for candidate in self.balance_candidates(cards).into_iter() {
+            let (lower, upper) = fuzz_range(candidate.interval);
+            let first = (candidate.reviewed + lower as i32).max(today + 1);
+            let last = candidate.reviewed + upper as i32;
+            if first >= last {
+                continue;
+            }
+
+            *load.get_mut(&candidate.due).unwrap() -= 1;
+            let cost = |day: i32| {
+                let weight = easy_days.0[self.weekday(day, utc_offset)];
+                let count = f64::from(load.get(&day).copied().unwrap_or(0) + 1);
+                if weight <= 0.0 {
+                    f64::INFINITY
+                } else {
+                    count / weight
+                }
+            };
+
+            // Prefer the least loaded day, then the one closest to the original date
+            let mut best = candidate.due;
+            let mut best_cost = cost(best);
+            for day in first..=last {
+                let day_cost = cost(day);
+                let closer = (day - candidate.due).abs() < (best - candidate.due).abs();
+                if day_cost < best_cost || (day_cost == best_cost && closer) {
+                    best = day;
+                    best_cost = day_cost;
+                }
+            }
+
+            *load.entry(best).or_default() += 1;
+            if best != candidate.due {
+                moves.push(Move {
+                    card: candidate.card,
+                    from: candidate.due - today,
+                    to: best - today,
+                });
+            }
+        }
+
+        BalancePlan {
+            before,
+            after: relative(&load),
+            moves,
+        }
+    }
+
+    // Balance the given cards, logging each move, and return what was done
+    pub fn balance(
+        &mut self,
+        cards: &[CardId],
+        easy_days: EasyDays,
+        utc_offset: i32,
+    ) -> BalancePlan {
+        let plan = self.balance_preview(cards, easy_days, utc_offset);
+        let today = self.today();
+
+        let mut next_id = self.new_revlog_id().0;
+        for change in plan.moves.iter() {
+            let card = match self.card_mut(change.card) {
+                Some(card) => card,
+                None => continue,
+            };
+            let last_interval = card.interval();
+            let interval = match last_interval {
+                Interval::Days(days) => days as i32 + change.to - change.from,
+                Interval::Seconds(_) => continue,
+            };
+            card.set_interval(Interval::Days(interval.max(1) as u32));
+            card.set_due(Due::Day(today + change.to));
+            card.touch();
+
+            let entry = ReviewLog::rescheduled(RevlogId(next_id), card, last_interval);
+            self.add_revlog(entry);
+            next_id += 1;
+        }
+
+        plan
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Fixture;
+
+    #[test]
+    fn fuzz_range_follows_anki() {
+        assert_eq!(fuzz_range(0), (0, 0));
+        assert_eq!(fuzz_range(2), (2, 2));
+        assert_eq!(fuzz_range(3), (2, 4));
+        assert_eq!(fuzz_range(10), (8, 12));
+        assert_eq!(fuzz_range(100), (93, 107));
+    }
+
+    #[test]
+    fn weekday_uses_the_local_date() {
+        let fixture = Fixture::new();
+        // Monday 2024-01-01 04:00 at UTC+10, still Sunday in UTC
+        fixture.sql("UPDATE col SET crt = 1704045600");
+        let collection = fixture.collection();
+        assert_eq!(collection.weekday(0, 600), 0);
+        assert_eq!(collection.weekday(0, 0), 6);
+        assert_eq!(collection.weekday(6, 600), 6);
+        assert_eq!(collection.weekday(7, 600), 0);
+
+        // Monday 2024-01-01 04:00 at UTC-5
+        fixture.sql("UPDATE col SET crt = 1704099600");
+        let collection = fixture.collection();
+        assert_eq!(collection.weekday(0, -300), 0);
+        assert_eq!(collection.weekday(-1, -300), 6);
+    }
+
+    #[test]
+    fn easy_days_are_avoided() {
+        // The fixture collection is on day 100, an interval of 10 can land 8 to 12 days
+        // after the last review
+        let fixture = Fixture::new();
+        fixture
+            .note(1, "", &["a", "b"])
+            .review_card(11, 1, 1, 110, 10);
+        let collection = fixture.collection();
+
+        let due = collection.weekday(110, 0);
+        let mut easy = EasyDays::default();
+        easy.0[due] = 0.0;
+        let plan = collection.balance_preview(&[CardId(11)], easy, 0);
+        assert_eq!(plan.moves.len(), 1);
+        let moved = plan.moves[0].to;
+        assert!((8..=12).contains(&moved));
+        assert_ne!(collection.weekday(100 + moved, 0), due);
+    }
+}
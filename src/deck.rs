@@ -4,16 +4,109 @@
  * See repository LICENSE for information.
  */
 
+use crate::media;
+use crate::tags;
 use json;
+use rusqlite::types::{FromSql, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
 use rusqlite::{params, Batch, Connection, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::ops::Deref;
 use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 // Information about database fields found at
 // https://github.com/ankidroid/Anki-Android/wiki/Database-Structure
 
+// Ids are all stored as i64 in the database, these newtypes keep them from being mixed up
+macro_rules! id_type {
+    ($name:ident) => {
+        #[derive(
+            Debug,
+            Clone,
+            Copy,
+            Default,
+            Serialize,
+            Deserialize,
+            PartialEq,
+            Eq,
+            PartialOrd,
+            Ord,
+            Hash,
+        )]
+        pub struct $name(pub i64);
+
+        impl From<i64> for $name {
+            fn from(i: i64) -> Self {
+                $name(i)
+            }
+        }
+
+        impl From<$name> for i64 {
+            fn from(val: $name) -> Self {
+                val.0
+            }
+        }
+
+        impl From<$name> for json::JsonValue {
+            fn from(val: $name) -> Self {
+                val.0.into()
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+
+        impl ToSql for $name {
+            fn to_sql(&self) -> Result<ToSqlOutput<'_>> {
+                self.0.to_sql()
+            }
+        }
+
+        impl FromSql for $name {
+            fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+                i64::column_result(value).map($name)
+            }
+        }
+    };
+}
+
+id_type!(NoteId);
+id_type!(CardId);
+id_type!(DeckId);
+id_type!(ModelId);
+id_type!(DeckConfigId);
+id_type!(RevlogId);
+
+// The current time in milliseconds since epoch
+pub(crate) fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+// Anki's duplicate check checksum, the first 8 hex digits of the SHA1 of the stripped first field
+fn field_checksum(text: &str) -> i64 {
+    i64::from_str_radix(&media::sha1_hex(text.as_bytes())[..8], 16).unwrap_or(0)
+}
+
+// Allocate a new id from the current time in milliseconds, bumping past any existing ids
+fn allocate_id(existing: impl Iterator<Item = i64>) -> i64 {
+    let now = now_millis();
+
+    match existing.max() {
+        Some(max) if max >= now => max + 1,
+        _ => now,
+    }
+}
+
 // Card type
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub enum CardType {
     New,
     Learning,
@@ -32,9 +125,9 @@ impl From<i64> for CardType {
     }
 }
 
-impl Into<i64> for CardType {
-    fn into(self) -> i64 {
-        match self {
+impl From<CardType> for i64 {
+    fn from(val: CardType) -> Self {
+        match val {
             CardType::New => 0,
             CardType::Learning => 1,
             CardType::Review => 2,
@@ -44,7 +137,7 @@ impl Into<i64> for CardType {
 }
 
 // Queue for card
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub enum CardQueue {
     UserBuried,
     Buried,
@@ -71,9 +164,9 @@ impl From<i64> for CardQueue {
     }
 }
 
-impl Into<i64> for CardQueue {
-    fn into(self) -> i64 {
-        match self {
+impl From<CardQueue> for i64 {
+    fn from(val: CardQueue) -> Self {
+        match val {
             CardQueue::UserBuried => -3,
             CardQueue::Buried => -2,
             CardQueue::Suspended => -1,
@@ -86,32 +179,231 @@ impl Into<i64> for CardQueue {
     }
 }
 
+// When a card is due, the meaning of the due column depends on the queue
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Due {
+    Position(u32),  // New cards, position in the new card queue
+    Day(i32),       // Review and day learning cards, days since collection creation
+    Timestamp(i64), // Learning cards, seconds since epoch
+}
+
+impl Due {
+    // Interpret the raw due column using the card type and queue
+    pub fn from_i64(i: i64, card_type: CardType, queue: CardQueue) -> Self {
+        let day = |i: i64| i32::try_from(i).map(Due::Day).unwrap_or(Due::Timestamp(i));
+        let position = |i: i64| {
+            u32::try_from(i)
+                .map(Due::Position)
+                .unwrap_or(Due::Timestamp(i))
+        };
+
+        match queue {
+            CardQueue::New => position(i),
+            CardQueue::Learning | CardQueue::Preview => Due::Timestamp(i),
+            CardQueue::Review | CardQueue::InLearning => day(i),
+            // Suspended and buried cards keep the due of the queue they came from
+            CardQueue::Suspended | CardQueue::Buried | CardQueue::UserBuried => match card_type {
+                CardType::New => position(i),
+                CardType::Review => day(i),
+                // Learning cards are due by timestamp unless they are in the day learning queue
+                CardType::Learning | CardType::Relearning => {
+                    if i > 1_000_000_000 {
+                        Due::Timestamp(i)
+                    } else {
+                        day(i)
+                    }
+                }
+            },
+        }
+    }
+}
+
+impl From<Due> for i64 {
+    fn from(val: Due) -> Self {
+        match val {
+            Due::Position(p) => p.into(),
+            Due::Day(d) => d.into(),
+            Due::Timestamp(t) => t,
+        }
+    }
+}
+
+// The interval of a card, stored as negative seconds or positive days
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Interval {
+    Seconds(u32),
+    Days(u32),
+}
+
+impl From<i64> for Interval {
+    fn from(i: i64) -> Self {
+        if i < 0 {
+            Interval::Seconds(u32::try_from(-i).unwrap_or(u32::MAX))
+        } else {
+            Interval::Days(u32::try_from(i).unwrap_or(u32::MAX))
+        }
+    }
+}
+
+impl From<Interval> for i64 {
+    fn from(val: Interval) -> Self {
+        match val {
+            Interval::Seconds(s) => -i64::from(s),
+            Interval::Days(d) => d.into(),
+        }
+    }
+}
+
 // The card as stored in the database
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Card {
-    id: i64,
-    note_id: i64,
-    deck_id: i64,
+    id: CardId,
+    note_id: NoteId,
+    deck_id: DeckId,
     ordinal: i64, // determines which of the card templates or cloze deletions it belongs to
     modification_time: i64, // seconds since epoch
     usn: i64,     // Update sequence number, used for syncs
     card_type: CardType,
-    queue: CardQueue, // Where in the queue is the card
-    due: i64,         // When the card is due, usage depends on card type
-    interval: i64,    // Interval, - is seconds, + is days
-    factor: i64,      // The ease factor of the card is parts per thousand (permille)
-    reps: i64,        // The number of reviews
+    queue: CardQueue,   // Where in the queue is the card
+    due: Due,           // When the card is due, usage depends on card type
+    interval: Interval, // Interval, - is seconds, + is days
+    factor: i64,        // The ease factor of the card is parts per thousand (permille)
+    reps: i64,          // The number of reviews
     lapses: i64,
-    left: i64,             // the number of reps left until graduation
-    original_due: i64,     // Original due
-    original_deck_id: i64, // Used for filtered decks
-    flags: i64,            // The card flags
+    left: i64,                // the number of reps left until graduation
+    original_due: i64,        // Original due
+    original_deck_id: DeckId, // Used for filtered decks
+    flags: i64,               // The card flags
 }
 
 impl Card {
+    pub fn id(&self) -> CardId {
+        self.id
+    }
+
+    pub fn note_id(&self) -> NoteId {
+        self.note_id
+    }
+
+    pub fn deck_id(&self) -> DeckId {
+        self.deck_id
+    }
+
+    pub fn original_deck_id(&self) -> DeckId {
+        self.original_deck_id
+    }
+
+    pub fn set_original_deck_id(&mut self, deck_id: DeckId) {
+        self.original_deck_id = deck_id;
+    }
+
+    pub fn card_type(&self) -> CardType {
+        self.card_type
+    }
+
+    pub fn queue(&self) -> CardQueue {
+        self.queue
+    }
+
+    pub fn set_queue(&mut self, queue: CardQueue) {
+        self.queue = queue;
+    }
+
+    pub fn set_card_type(&mut self, card_type: CardType) {
+        self.card_type = card_type;
+    }
+
+    // The queue a card belongs in when it is not suspended or buried
+    pub fn natural_queue(&self) -> CardQueue {
+        match self.card_type {
+            CardType::New => CardQueue::New,
+            CardType::Review => CardQueue::Review,
+            CardType::Learning | CardType::Relearning => match self.due {
+                Due::Day(_) => CardQueue::InLearning,
+                _ => CardQueue::Learning,
+            },
+        }
+    }
+
+    // Mark the card as modified so it syncs
+    pub fn touch(&mut self) {
+        self.modification_time = now_millis() / 1000;
+        self.usn = -1;
+    }
+
+    pub fn due(&self) -> Due {
+        self.due
+    }
+
+    pub fn set_due(&mut self, due: Due) {
+        self.due = due;
+    }
+
+    pub fn interval(&self) -> Interval {
+        self.interval
+    }
+
+    pub fn ordinal(&self) -> i64 {
+        self.ordinal
+    }
+
+    pub fn factor(&self) -> i64 {
+        self.factor
+    }
+
+    pub fn set_factor(&mut self, factor: i64) {
+        self.factor = factor;
+    }
+
+    pub fn reps(&self) -> i64 {
+        self.reps
+    }
+
+    pub fn set_reps(&mut self, reps: i64) {
+        self.reps = reps;
+    }
+
+    pub fn lapses(&self) -> i64 {
+        self.lapses
+    }
+
+    pub fn set_lapses(&mut self, lapses: i64) {
+        self.lapses = lapses;
+    }
+
+    pub fn left(&self) -> i64 {
+        self.left
+    }
+
+    pub fn set_left(&mut self, left: i64) {
+        self.left = left;
+    }
+
+    pub fn original_due(&self) -> i64 {
+        self.original_due
+    }
+
+    pub fn set_original_due(&mut self, original_due: i64) {
+        self.original_due = original_due;
+    }
+
+    pub fn flags(&self) -> i64 {
+        self.flags
+    }
+
+    pub fn set_flags(&mut self, flags: i64) {
+        self.flags = flags;
+    }
+
+    pub fn set_interval(&mut self, interval: Interval) {
+        self.interval = interval;
+    }
+
     pub fn save(self, conn: &Connection) -> Result<()> {
         let card_type: i64 = self.card_type.into();
         let card_queue: i64 = self.queue.into();
+        let due: i64 = self.due.into();
+        let interval: i64 = self.interval.into();
         conn.execute("INSERT INTO cards (id, nid, did, ord, mod, usn, type, queue, due, ivl, factor, reps, lapses, left, odue, odid, flags, data) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18);",
         params![
             self.id,
@@ -122,8 +414,8 @@ impl Card {
             self.usn,
             card_type,
             card_queue,
-            self.due,
-            self.interval,
+            due,
+            interval,
             self.factor,
             self.reps,
             self.lapses,
@@ -149,6 +441,8 @@ impl Card {
             for item in v.into_iter() {
                 let card_type: i64 = item.card_type.into();
                 let card_queue: i64 = item.queue.into();
+                let due: i64 = item.due.into();
+                let interval: i64 = item.interval.into();
                 stmt.execute(params![
                     item.id,
                     item.note_id,
@@ -158,8 +452,8 @@ impl Card {
                     item.usn,
                     card_type,
                     card_queue,
-                    item.due,
-                    item.interval,
+                    due,
+                    interval,
                     item.factor,
                     item.reps,
                     item.lapses,
@@ -188,6 +482,14 @@ pub struct Field {
 }
 
 impl Field {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn ordinal(&self) -> i64 {
+        self.ordinal
+    }
+
     pub fn new(json: &json::JsonValue) -> json::JsonResult<Self> {
         if !json.is_object() {
             return Err(json::JsonError::WrongType(String::from(
@@ -282,7 +584,7 @@ pub struct Template {
     answer_format: String,
     back_format: String,
     browser_format: String,
-    deck_override: Option<i64>,
+    deck_override: Option<DeckId>,
     name: String,
     ordinal: i64,
     question_format: String,
@@ -391,9 +693,9 @@ impl From<i64> for ModelType {
     }
 }
 
-impl Into<i64> for ModelType {
-    fn into(self) -> i64 {
-        match self {
+impl From<ModelType> for i64 {
+    fn from(val: ModelType) -> Self {
+        match val {
             ModelType::Standard => 0,
             ModelType::Cloze => 1,
         }
@@ -403,10 +705,10 @@ impl Into<i64> for ModelType {
 // Model of note as stored in the database
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Model {
-    epoch: i64,
-    id: i64,
+    epoch: ModelId,
+    id: ModelId,
     css: String,
-    deck_id: Option<i64>,
+    deck_id: Option<DeckId>,
     fields: Vec<Field>,
     latex_post: String,
     latex_pre: String,
@@ -420,6 +722,40 @@ pub struct Model {
 }
 
 impl Template {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn ordinal(&self) -> i64 {
+        self.ordinal
+    }
+
+    pub fn question_format(&self) -> &str {
+        &self.question_format
+    }
+
+    pub fn answer_format(&self) -> &str {
+        &self.answer_format
+    }
+
+    // Formats used in the browser instead of the question and answer, often empty
+    pub fn browser_formats(&self) -> (&str, &str) {
+        (&self.browser_format, &self.back_format)
+    }
+
+    pub fn set_question_format(&mut self, format: String) {
+        self.question_format = format;
+    }
+
+    pub fn set_answer_format(&mut self, format: String) {
+        self.answer_format = format;
+    }
+
+    pub fn set_browser_formats(&mut self, question: String, answer: String) {
+        self.browser_format = question;
+        self.back_format = answer;
+    }
+
     pub fn new(json: &json::JsonValue) -> json::JsonResult<Self> {
         let mut template = Template {
             answer_format: String::from(""),
@@ -479,7 +815,7 @@ impl Template {
         }
 
         if let Some(over) = json["did"].as_i64() {
-            template.deck_override = Some(over);
+            template.deck_override = Some(over.into());
         }
 
         if let Some(ord) = json["ord"].as_i64() {
@@ -524,11 +860,54 @@ impl Template {
 }
 
 impl Model {
+    pub fn id(&self) -> ModelId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn model_type(&self) -> ModelType {
+        self.model_type.clone()
+    }
+
+    pub fn fields(&self) -> &[Field] {
+        &self.fields
+    }
+
+    pub fn css(&self) -> &str {
+        &self.css
+    }
+
+    pub fn set_css(&mut self, css: String) {
+        self.css = css;
+    }
+
+    // Index of the field shown in the browser's sort column
+    pub fn sort_field(&self) -> usize {
+        self.sort_field.max(0) as usize
+    }
+
+    pub fn templates(&self) -> &[Template] {
+        &self.templates
+    }
+
+    pub fn templates_mut(&mut self) -> &mut [Template] {
+        &mut self.templates
+    }
+
+    // Mark the model as modified so it syncs
+    pub fn touch(&mut self) {
+        self.modification_time = now_millis() / 1000;
+        self.usn = -1;
+    }
+
     // Parse a model from a JSON object
-    pub fn new(epoch: i64, json_model: &json::JsonValue) -> json::Result<Self> {
+    pub fn new(epoch: ModelId, json_model: &json::JsonValue) -> json::Result<Self> {
         let mut model = Model {
             epoch,
-            id: 0,
+            id: ModelId(0),
             css: String::from(""),
             deck_id: None,
             fields: Vec::new(),
@@ -563,19 +942,19 @@ impl Model {
 
         // Can be missing
         if let Some(deck_id) = json_model["did"].as_i64() {
-            model.deck_id = Some(deck_id);
+            model.deck_id = Some(deck_id.into());
         } else if let Some(deck_id) = json_model["did"].as_str() {
             let deck_id = deck_id.parse::<i64>();
-            if let Err(_) = deck_id {
+            if deck_id.is_err() {
                 return Err(json::JsonError::WrongType(String::from(
                     "Deck ID field missing or incorrect",
                 )));
             }
-            model.deck_id = Some(deck_id.unwrap());
+            model.deck_id = Some(deck_id.unwrap().into());
         }
 
         if let Some(id) = json_model["id"].as_i64() {
-            model.id = id;
+            model.id = id.into();
         } else {
             return Err(json::JsonError::WrongType(String::from(
                 "ID field missing or incorrect",
@@ -639,7 +1018,7 @@ impl Model {
         }
 
         // Parse the req field, if it's there
-        let ref req = json_model["req"];
+        let req = &json_model["req"];
         if req.is_array() {
             let mut req_vec: Vec<Request> = Vec::new();
             for member in req.members() {
@@ -650,7 +1029,7 @@ impl Model {
         }
 
         // Parse the template field
-        let ref templates = json_model["tmpls"];
+        let templates = &json_model["tmpls"];
         if !templates.is_array() {
             return Err(json::JsonError::WrongType(String::from(
                 "tmpls is not array",
@@ -661,7 +1040,7 @@ impl Model {
             model.templates.push(Template::new(member)?);
         }
 
-        let ref fields = json_model["flds"];
+        let fields = &json_model["flds"];
         if !fields.is_array() {
             return Err(json::JsonError::WrongType(String::from(
                 "flds is not array",
@@ -689,20 +1068,20 @@ impl Model {
 
         for (epoch, model) in parsed.entries() {
             let epoch = epoch.parse::<i64>();
-            if let Err(_) = epoch {
+            if epoch.is_err() {
                 return Err(json::JsonError::WrongType(String::from(
                     "Model does not have proper id",
                 )));
             }
             let epoch = epoch.unwrap();
 
-            models.push(Model::new(epoch, model)?);
+            models.push(Model::new(epoch.into(), model)?);
         }
 
         Ok(models)
     }
 
-    pub fn to_json(self) -> (i64, json::JsonValue) {
+    pub fn to_json(self) -> (ModelId, json::JsonValue) {
         let mut json = object! {
             css: self.css,
             id: self.id,
@@ -756,9 +1135,9 @@ impl Model {
 // The note as stored in the database
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Note {
-    id: i64,             // Note id
+    id: NoteId,          // Note id
     guid: String,        // Globally unique ID
-    model_id: i64,       // Model ID
+    model_id: ModelId,   // Model ID
     mod_time: i64,       // Modification time
     usn: i64,            // update sequence number
     tags: Vec<String>,   // tags on the note
@@ -768,6 +1147,47 @@ pub struct Note {
 }
 
 impl Note {
+    pub fn id(&self) -> NoteId {
+        self.id
+    }
+
+    pub fn model_id(&self) -> ModelId {
+        self.model_id
+    }
+
+    pub fn guid(&self) -> &str {
+        &self.guid
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    // Replace the tags on the note, marking it modified
+    pub fn set_tags(&mut self, tags: Vec<String>) {
+        self.tags = tags;
+        self.touch();
+    }
+
+    // Mark the note as modified so it syncs
+    pub fn touch(&mut self) {
+        self.mod_time = now_millis() / 1000;
+        self.usn = -1;
+    }
+
+    // Tags are stored space separated with a space on each end
+    fn tag_string(&self) -> String {
+        if self.tags.is_empty() {
+            String::new()
+        } else {
+            format!(" {} ", self.tags.join(" "))
+        }
+    }
+
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
     pub fn save(self, conn: &Connection) -> Result<()> {
         conn.execute("INSERT INTO notes (id, guid, mid, mod, usn, tags, flds, sfld, csum, flags, data) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
             params![
@@ -776,8 +1196,8 @@ impl Note {
                 self.model_id,
                 self.mod_time,
                 self.usn,
-                self.tags.join(" "),
-                self.fields.join("\0x1f"),
+                self.tag_string(),
+                self.fields.join("\x1f"),
                 self.sort_field,
                 self.sum,
                 0,
@@ -802,8 +1222,8 @@ impl Note {
                     item.model_id,
                     item.mod_time,
                     item.usn,
-                    item.tags.join(" "),
-                    item.fields.join("\0x1f"),
+                    item.tag_string(),
+                    item.fields.join("\x1f"),
                     item.sort_field,
                     item.sum,
                     0,
@@ -819,7 +1239,7 @@ impl Note {
 // A deck as stored in the database
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Deck {
-    epoch: i64,
+    epoch: DeckId,
     name: String,
     extended_review_limit: i64,
     usn: i64,
@@ -827,8 +1247,8 @@ pub struct Deck {
     browser_collapsed: bool,
     dynamic: i64,
     extended_new_limit: i64,
-    config_id: i64,
-    id: i64,
+    config_id: DeckConfigId,
+    id: DeckId,
     modification_time: i64,
     description: String,
     new_today: (i64, i64),
@@ -837,8 +1257,24 @@ pub struct Deck {
 }
 
 impl Deck {
+    pub fn id(&self) -> DeckId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn config_id(&self) -> DeckConfigId {
+        self.config_id
+    }
+
+    pub fn is_dynamic(&self) -> bool {
+        self.dynamic != 0
+    }
+
     // Parse a single deck JSON
-    pub fn new(epoch: i64, json: &json::JsonValue) -> json::JsonResult<Deck> {
+    pub fn new(epoch: DeckId, json: &json::JsonValue) -> json::JsonResult<Deck> {
         let mut deck = Deck {
             epoch,
             name: String::new(),
@@ -848,8 +1284,8 @@ impl Deck {
             browser_collapsed: false,
             dynamic: 0,
             extended_new_limit: 10,
-            config_id: 0,
-            id: 0,
+            config_id: DeckConfigId(0),
+            id: DeckId(0),
             modification_time: 0,
             description: String::new(),
             new_today: (0, 0),
@@ -915,7 +1351,7 @@ impl Deck {
         }
 
         if let Some(conf) = json["conf"].as_i64() {
-            deck.config_id = conf;
+            deck.config_id = conf.into();
         } else {
             return Err(json::JsonError::WrongType(String::from(
                 "Deck conf field missing or incorect",
@@ -923,7 +1359,7 @@ impl Deck {
         }
 
         if let Some(id) = json["id"].as_i64() {
-            deck.id = id;
+            deck.id = id.into();
         } else {
             return Err(json::JsonError::WrongType(String::from(
                 "Deck id field missing or incorect",
@@ -947,7 +1383,7 @@ impl Deck {
         }
 
         // Now, parse the tuples
-        let ref new_today = json["newToday"];
+        let new_today = &json["newToday"];
         if !new_today.is_array() {
             return Err(json::JsonError::WrongType(String::from(
                 "Deck newToday field missing or incorect",
@@ -974,7 +1410,7 @@ impl Deck {
             )));
         }
 
-        let ref learned_today = json["lrnToday"];
+        let learned_today = &json["lrnToday"];
         if !learned_today.is_array() {
             return Err(json::JsonError::WrongType(String::from(
                 "Deck lrnToday field missing or incorect",
@@ -1001,7 +1437,7 @@ impl Deck {
             )));
         }
 
-        let ref review_today = json["lrnToday"];
+        let review_today = &json["lrnToday"];
         if !review_today.is_array() {
             return Err(json::JsonError::WrongType(String::from(
                 "Deck revToday field missing or incorect",
@@ -1046,20 +1482,20 @@ impl Deck {
         // Every deck will be a key in the object with the key being the epoch id
         for (deck_epoch, deck_json) in parsed.entries() {
             let deck_epoch = deck_epoch.parse::<i64>();
-            if let Err(_) = deck_epoch {
+            if deck_epoch.is_err() {
                 return Err(json::JsonError::WrongType(String::from(
                     "Deck does not have proper id",
                 )));
             }
             let deck_epoch = deck_epoch.unwrap();
 
-            decks.push(Deck::new(deck_epoch, deck_json)?);
+            decks.push(Deck::new(deck_epoch.into(), deck_json)?);
         }
 
         Ok(decks)
     }
 
-    pub fn to_json(self) -> (i64, json::JsonValue) {
+    pub fn to_json(self) -> (DeckId, json::JsonValue) {
         let json = object! {
             name: self.name,
             extendRev: self.extended_review_limit,
@@ -1108,9 +1544,9 @@ impl From<i64> for LeechAction {
     }
 }
 
-impl Into<i64> for LeechAction {
-    fn into(self) -> i64 {
-        match self {
+impl From<LeechAction> for i64 {
+    fn from(val: LeechAction) -> Self {
+        match val {
             LeechAction::Suspend => 0,
             LeechAction::Mark => 1,
         }
@@ -1128,6 +1564,29 @@ pub struct LapsedConfig {
 }
 
 impl LapsedConfig {
+    pub fn leech_action(&self) -> LeechAction {
+        self.leech_action.clone()
+    }
+
+    // Lapses before a card becomes a leech
+    pub fn leech_fails(&self) -> i64 {
+        self.leech_fails
+    }
+
+    // Relearning steps in minutes
+    pub fn delays(&self) -> &[f64] {
+        &self.delays
+    }
+
+    pub fn min_interval(&self) -> i64 {
+        self.min_interval
+    }
+
+    // Multiplier applied to the interval of a lapsed card
+    pub fn mult(&self) -> f64 {
+        self.mult
+    }
+
     pub fn new(json: &json::JsonValue) -> json::JsonResult<Self> {
         let mut lapsed = LapsedConfig {
             delays: Vec::new(),
@@ -1176,7 +1635,7 @@ impl LapsedConfig {
             )));
         }
 
-        let ref delays = json["delays"];
+        let delays = &json["delays"];
         if !delays.is_array() {
             return Err(json::JsonError::WrongType(String::from(
                 "leech delays field missing or incorrect",
@@ -1231,9 +1690,9 @@ impl From<i64> for NewOrder {
     }
 }
 
-impl Into<i64> for NewOrder {
-    fn into(self) -> i64 {
-        match self {
+impl From<NewOrder> for i64 {
+    fn from(val: NewOrder) -> Self {
+        match val {
             NewOrder::Random => 0,
             NewOrder::Due => 1,
         }
@@ -1253,6 +1712,28 @@ pub struct NewConfig {
 }
 
 impl NewConfig {
+    // Learning steps in minutes
+    pub fn delays(&self) -> &[f64] {
+        &self.delays
+    }
+
+    pub fn initial_factor(&self) -> i64 {
+        self.initial_factor
+    }
+
+    // Graduating and easy intervals in days
+    pub fn intervals(&self) -> &[i64] {
+        &self.intervals
+    }
+
+    pub fn order(&self) -> NewOrder {
+        self.order.clone()
+    }
+
+    pub fn per_day(&self) -> i64 {
+        self.per_day
+    }
+
     pub fn new(json: &json::JsonValue) -> json::JsonResult<Self> {
         let mut new = NewConfig {
             bury: false,
@@ -1304,7 +1785,7 @@ impl NewConfig {
         }
 
         // Parse the lists
-        let ref delays = json["delays"];
+        let delays = &json["delays"];
         if !delays.is_array() {
             return Err(json::JsonError::WrongType(String::from(
                 "new delays field missing or incorrect",
@@ -1321,7 +1802,7 @@ impl NewConfig {
             }
         }
 
-        let ref ints = json["ints"];
+        let ints = &json["ints"];
         if !ints.is_array() {
             return Err(json::JsonError::WrongType(String::from(
                 "new ints field missing or incorrect",
@@ -1380,6 +1861,27 @@ pub struct ReviewConfig {
 }
 
 impl ReviewConfig {
+    // Extra multiplier for the easy button
+    pub fn ease4(&self) -> f64 {
+        self.ease4
+    }
+
+    pub fn fuzz(&self) -> Option<f64> {
+        self.fuzz
+    }
+
+    pub fn interval_factor(&self) -> f64 {
+        self.interval_factor
+    }
+
+    pub fn max_interval(&self) -> f64 {
+        self.max_interval
+    }
+
+    pub fn per_day(&self) -> i64 {
+        self.per_day
+    }
+
     pub fn new(json: &json::JsonValue) -> json::JsonResult<Self> {
         let mut rev = ReviewConfig {
             bury: false,
@@ -1465,7 +1967,7 @@ impl ReviewConfig {
 // The deck configuration as stored in the database
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeckConfig {
-    id: i64,
+    id: DeckConfigId,
     autoplay: bool,
     dynamic: bool,
     lapse: Option<LapsedConfig>,
@@ -1480,7 +1982,23 @@ pub struct DeckConfig {
 }
 
 impl DeckConfig {
-    pub fn new(id: i64, json: &json::JsonValue) -> json::JsonResult<Self> {
+    pub fn id(&self) -> DeckConfigId {
+        self.id
+    }
+
+    pub fn new_config(&self) -> Option<&NewConfig> {
+        self.new.as_ref()
+    }
+
+    pub fn lapse_config(&self) -> Option<&LapsedConfig> {
+        self.lapse.as_ref()
+    }
+
+    pub fn review_config(&self) -> Option<&ReviewConfig> {
+        self.review.as_ref()
+    }
+
+    pub fn new(id: DeckConfigId, json: &json::JsonValue) -> json::JsonResult<Self> {
         let mut conf = DeckConfig {
             id,
             autoplay: false,
@@ -1589,19 +2107,19 @@ impl DeckConfig {
 
         for (conf_id, conf_json) in parsed.entries() {
             let conf_id = conf_id.parse::<i64>();
-            if let Err(_) = conf_id {
+            if conf_id.is_err() {
                 return Err(json::JsonError::WrongType(String::from(
                     "Deck config key is not an id",
                 )));
             }
 
-            confs.push(DeckConfig::new(conf_id.unwrap(), conf_json)?);
+            confs.push(DeckConfig::new(conf_id.unwrap().into(), conf_json)?);
         }
 
         Ok(confs)
     }
 
-    pub fn to_json(self) -> (i64, json::JsonValue) {
+    pub fn to_json(self) -> (DeckConfigId, json::JsonValue) {
         let mut json = object! {
             autoplay: self.autoplay,
             "dyn": self.dynamic,
@@ -1650,9 +2168,9 @@ impl From<i64> for NewSpread {
     }
 }
 
-impl Into<i64> for NewSpread {
-    fn into(self) -> i64 {
-        match self {
+impl From<NewSpread> for i64 {
+    fn from(val: NewSpread) -> Self {
+        match val {
             NewSpread::Distribute => 0,
             NewSpread::Last => 1,
             NewSpread::First => 2,
@@ -1660,17 +2178,25 @@ impl Into<i64> for NewSpread {
     }
 }
 
+// Which of Anki's schedulers the collection is using
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SchedulerVersion {
+    V1,
+    V2,
+    V3,
+}
+
 // Synced configuration options as represented in the database
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SyncConfig {
-    current_deck: i64,
-    active_decks: Vec<i64>,
+    current_deck: DeckId,
+    active_decks: Vec<DeckId>,
     new_spread: NewSpread,
     collapse_time: i64,
     time_limit: i64,
     estimated_times: bool,
     due_counts: bool,
-    current_model: i64,
+    current_model: ModelId,
     next_pos: i64,
     sort_type: Option<String>,
     sort_backwards: bool,
@@ -1678,20 +2204,70 @@ pub struct SyncConfig {
     day_learn_first: bool,
     new_bury: Option<bool>,
     last_unburied: Option<i64>,
+    sched_ver: Option<i64>,
+    sched_2021: Option<bool>,
+    rollover: Option<i64>,
     active_cols: Vec<String>,
 }
 
 impl SyncConfig {
+    pub fn last_unburied(&self) -> Option<i64> {
+        self.last_unburied
+    }
+
+    pub fn set_last_unburied(&mut self, day: i64) {
+        self.last_unburied = Some(day);
+    }
+
+    pub fn scheduler_version(&self) -> SchedulerVersion {
+        match (self.sched_ver, self.sched_2021) {
+            (_, Some(true)) => SchedulerVersion::V3,
+            (Some(ver), _) if ver >= 2 => SchedulerVersion::V2,
+            _ => SchedulerVersion::V1,
+        }
+    }
+
+    pub fn set_scheduler_version(&mut self, version: SchedulerVersion) {
+        match version {
+            SchedulerVersion::V1 => {
+                self.sched_ver = Some(1);
+                self.sched_2021 = self.sched_2021.map(|_| false);
+            }
+            SchedulerVersion::V2 => {
+                self.sched_ver = Some(2);
+                self.sched_2021 = self.sched_2021.map(|_| false);
+            }
+            SchedulerVersion::V3 => {
+                self.sched_ver = Some(2);
+                self.sched_2021 = Some(true);
+            }
+        }
+    }
+
+    // Hour of the day, in local time, that a new collection day starts at
+    pub fn rollover(&self) -> i64 {
+        self.rollover.unwrap_or(4)
+    }
+
+    // Position given to the next new card
+    pub fn next_pos(&self) -> i64 {
+        self.next_pos
+    }
+
+    pub fn set_next_pos(&mut self, pos: i64) {
+        self.next_pos = pos;
+    }
+
     pub fn new(data: &str) -> json::JsonResult<Self> {
         let mut conf = SyncConfig {
-            current_deck: 0,
+            current_deck: DeckId(0),
             active_decks: Vec::new(),
             new_spread: NewSpread::Distribute,
             collapse_time: 0,
             time_limit: 0,
             estimated_times: false,
             due_counts: false,
-            current_model: 0,
+            current_model: ModelId(0),
             next_pos: 0,
             sort_type: None,
             sort_backwards: false,
@@ -1699,6 +2275,9 @@ impl SyncConfig {
             day_learn_first: false,
             new_bury: None,
             last_unburied: None,
+            sched_ver: None,
+            sched_2021: None,
+            rollover: None,
             active_cols: Vec::new(),
         };
 
@@ -1712,7 +2291,7 @@ impl SyncConfig {
 
         // Get the options from the JSON
         if let Some(cur) = json["curDeck"].as_i64() {
-            conf.current_deck = cur;
+            conf.current_deck = cur.into();
         } else {
             return Err(json::JsonError::WrongType(String::from(
                 "SyncConfig curDeck field is missing or incorrect",
@@ -1760,7 +2339,7 @@ impl SyncConfig {
         }
 
         if let Some(cur) = json["curModel"].as_i64() {
-            conf.current_model = cur;
+            conf.current_model = cur.into();
         } else {
             return Err(json::JsonError::WrongType(String::from(
                 "SyncConfig curModel field is missing or incorrect",
@@ -1814,8 +2393,23 @@ impl SyncConfig {
             conf.last_unburied = Some(last);
         }
 
+        // Can be missing, v1 collections do not set it
+        if let Some(ver) = json["schedVer"].as_i64() {
+            conf.sched_ver = Some(ver);
+        }
+
+        // Can be missing, set when the v3 scheduler is enabled
+        if let Some(v3) = json["sched2021"].as_bool() {
+            conf.sched_2021 = Some(v3);
+        }
+
+        // Can be missing, days then start at 4am
+        if let Some(hour) = json["rollover"].as_i64() {
+            conf.rollover = Some(hour);
+        }
+
         // Parse the lists
-        let ref active = json["activeDecks"];
+        let active = &json["activeDecks"];
         if !active.is_array() {
             return Err(json::JsonError::WrongType(String::from(
                 "SyncConfig activeDecks field is missing or incorrect",
@@ -1824,7 +2418,7 @@ impl SyncConfig {
 
         for j in active.members() {
             if let Some(i) = j.as_i64() {
-                conf.active_decks.push(i);
+                conf.active_decks.push(i.into());
             } else {
                 return Err(json::JsonError::WrongType(String::from(
                     "SyncConfig activeDecks contains non number",
@@ -1833,7 +2427,7 @@ impl SyncConfig {
         }
 
         // This one can be missing
-        let ref active = json["activeCols"];
+        let active = &json["activeCols"];
         if active.is_array() {
             for j in active.members() {
                 if let Some(col) = j.as_str() {
@@ -1861,6 +2455,7 @@ impl SyncConfig {
     pub fn to_json(self) -> json::JsonValue {
         let mut json = object! {
             curDeck: self.current_deck,
+            newSpread: i64::from(self.new_spread),
             collapseTime: self.collapse_time,
             timeLim: self.time_limit,
             estTimes: self.estimated_times,
@@ -1890,7 +2485,22 @@ impl SyncConfig {
             json.insert("lastUnburied", i).unwrap();
         }
 
-        let active_cols: String = self.active_cols.join(" ");
+        if let Some(i) = self.sched_ver {
+            json.insert("schedVer", i).unwrap();
+        }
+
+        if let Some(b) = self.sched_2021 {
+            json.insert("sched2021", b).unwrap();
+        }
+
+        if let Some(i) = self.rollover {
+            json.insert("rollover", i).unwrap();
+        }
+
+        let mut active_cols = array! {};
+        for col in self.active_cols.into_iter() {
+            active_cols.push(col).unwrap();
+        }
         json.insert("activeCols", active_cols).unwrap();
 
         json
@@ -1898,7 +2508,7 @@ impl SyncConfig {
 }
 
 // Which answer button was pressed in a review
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub enum ReviewAnswer {
     Wrong,
     Hard,
@@ -1908,60 +2518,170 @@ pub enum ReviewAnswer {
 
 // To/from i64 is a bit more complicated
 impl ReviewAnswer {
-    pub fn from_i64(is_review: bool, i: i64) -> Self {
-        if is_review {
+    // The v1 scheduler only showed three buttons for learning cards, without hard
+    fn three_buttons(kind: RevlogKind, version: SchedulerVersion) -> bool {
+        version == SchedulerVersion::V1
+            && matches!(kind, RevlogKind::Learning | RevlogKind::Relearning)
+    }
+
+    // Entries without an answer, such as manual and rescheduled ones, are stored as 0
+    pub fn from_i64(kind: RevlogKind, version: SchedulerVersion, i: i64) -> Option<Self> {
+        if i == 0 {
+            return None;
+        }
+
+        if Self::three_buttons(kind, version) {
             match i {
-                2 => ReviewAnswer::Hard,
-                3 => ReviewAnswer::OK,
-                4 => ReviewAnswer::Easy,
-                _ => ReviewAnswer::Wrong,
+                2 => Some(ReviewAnswer::OK),
+                3 => Some(ReviewAnswer::Easy),
+                _ => Some(ReviewAnswer::Wrong),
             }
         } else {
             match i {
-                2 => ReviewAnswer::OK,
-                3 => ReviewAnswer::Easy,
-                _ => ReviewAnswer::Wrong,
+                2 => Some(ReviewAnswer::Hard),
+                3 => Some(ReviewAnswer::OK),
+                4 => Some(ReviewAnswer::Easy),
+                _ => Some(ReviewAnswer::Wrong),
             }
         }
     }
 
-    pub fn into_i64(self, is_review: bool) -> i64 {
-        if is_review {
-            match self {
+    pub fn into_i64(answer: Option<Self>, kind: RevlogKind, version: SchedulerVersion) -> i64 {
+        let answer = match answer {
+            Some(answer) => answer,
+            None => return 0,
+        };
+
+        if Self::three_buttons(kind, version) {
+            // Hard does not exist here, it is closest to a pass
+            match answer {
                 ReviewAnswer::Wrong => 1,
-                ReviewAnswer::Hard => 2,
-                ReviewAnswer::OK => 3,
-                ReviewAnswer::Easy => 4,
+                ReviewAnswer::Hard | ReviewAnswer::OK => 2,
+                ReviewAnswer::Easy => 3,
             }
         } else {
-            match self {
+            match answer {
                 ReviewAnswer::Wrong => 1,
-                ReviewAnswer::OK => 2,
-                ReviewAnswer::Easy => 3,
-                ReviewAnswer::Hard => 1,
+                ReviewAnswer::Hard => 2,
+                ReviewAnswer::OK => 3,
+                ReviewAnswer::Easy => 4,
             }
         }
     }
 }
 
+// What kind of review a review log entry records
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum RevlogKind {
+    Learning,
+    Review,
+    Relearning,
+    Filtered,
+    Manual,
+    Rescheduled,
+}
+
+impl From<i64> for RevlogKind {
+    fn from(i: i64) -> Self {
+        match i {
+            1 => RevlogKind::Review,
+            2 => RevlogKind::Relearning,
+            3 => RevlogKind::Filtered,
+            4 => RevlogKind::Manual,
+            5 => RevlogKind::Rescheduled,
+            _ => RevlogKind::Learning,
+        }
+    }
+}
+
+impl From<RevlogKind> for i64 {
+    fn from(val: RevlogKind) -> Self {
+        match val {
+            RevlogKind::Learning => 0,
+            RevlogKind::Review => 1,
+            RevlogKind::Relearning => 2,
+            RevlogKind::Filtered => 3,
+            RevlogKind::Manual => 4,
+            RevlogKind::Rescheduled => 5,
+        }
+    }
+}
+
 // The review log as stored in the database
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ReviewLog {
-    id: i64,             // epoch-milliseconds of when the review was done
-    card_id: i64,        // Card id
-    usn: i64,            // update sequence number
-    ease: ReviewAnswer,  // Which button was pressed on the review
-    interval: i64,       // Card interval
-    last_interval: i64,  // Previous card interval
-    factor: i64,         // factor
-    time: i64,           // How long the review took in milliseconds
-    card_type: CardType, // As in card_db
+    id: RevlogId,               // epoch-milliseconds of when the review was done
+    card_id: CardId,            // Card id
+    usn: i64,                   // update sequence number
+    ease: Option<ReviewAnswer>, // Which button was pressed on the review, if any
+    interval: i64,              // Card interval
+    last_interval: i64,         // Previous card interval
+    factor: i64,                // factor
+    time: i64,                  // How long the review took in milliseconds
+    kind: RevlogKind,           // What kind of review this was
 }
 
 impl ReviewLog {
-    pub fn save(self, conn: &Connection) -> Result<()> {
-        let ease: i64 = self.ease.into_i64(self.card_type == CardType::Review);
-        let card_type: i64 = self.card_type.into();
+    // An entry recording that a card was rescheduled by hand, with no answer given
+    pub fn manual(id: RevlogId, card: &Card, last_interval: Interval) -> Self {
+        ReviewLog::unanswered(id, card, last_interval, RevlogKind::Manual)
+    }
+
+    // An entry recording that a card's due date was moved automatically
+    pub fn rescheduled(id: RevlogId, card: &Card, last_interval: Interval) -> Self {
+        ReviewLog::unanswered(id, card, last_interval, RevlogKind::Rescheduled)
+    }
+
+    fn unanswered(id: RevlogId, card: &Card, last_interval: Interval, kind: RevlogKind) -> Self {
+        ReviewLog {
+            id,
+            card_id: card.id,
+            usn: -1,
+            ease: None,
+            interval: card.interval.into(),
+            last_interval: last_interval.into(),
+            factor: card.factor,
+            time: 0,
+            kind,
+        }
+    }
+
+    pub fn id(&self) -> RevlogId {
+        self.id
+    }
+
+    pub fn card_id(&self) -> CardId {
+        self.card_id
+    }
+
+    pub fn ease(&self) -> Option<ReviewAnswer> {
+        self.ease
+    }
+
+    pub fn kind(&self) -> RevlogKind {
+        self.kind
+    }
+
+    pub fn interval(&self) -> Interval {
+        self.interval.into()
+    }
+
+    pub fn last_interval(&self) -> Interval {
+        self.last_interval.into()
+    }
+
+    pub fn factor(&self) -> i64 {
+        self.factor
+    }
+
+    // Time taken in milliseconds
+    pub fn time(&self) -> i64 {
+        self.time
+    }
+
+    pub fn save(self, conn: &Connection, version: SchedulerVersion) -> Result<()> {
+        let ease = ReviewAnswer::into_i64(self.ease, self.kind, version);
+        let kind: i64 = self.kind.into();
         conn.execute("INSERT INTO revlog (id, cid, usn, ease, ivl, lastIvl, factor, time, type) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9);",
             params![
                 self.id,
@@ -1972,12 +2692,12 @@ impl ReviewLog {
                 self.last_interval,
                 self.factor,
                 self.time,
-                card_type,
+                kind,
             ])?;
         Ok(())
     }
 
-    pub fn save_all(conn: &Connection, v: Vec<Self>) -> Result<()> {
+    pub fn save_all(conn: &Connection, v: Vec<Self>, version: SchedulerVersion) -> Result<()> {
         let sql = r"INSERT INTO revlog (
                 id, cid, usn, ease, ivl, lastIvl, factor, time, type
             ) VALUES (
@@ -1987,8 +2707,8 @@ impl ReviewLog {
         let mut batch = Batch::new(conn, sql);
         if let Some(mut stmt) = batch.next()? {
             for item in v.into_iter() {
-                let ease: i64 = item.ease.into_i64(item.card_type == CardType::Review);
-                let card_type: i64 = item.card_type.into();
+                let ease = ReviewAnswer::into_i64(item.ease, item.kind, version);
+                let kind: i64 = item.kind.into();
                 stmt.execute(params![
                     item.id,
                     item.card_id,
@@ -1998,7 +2718,7 @@ impl ReviewLog {
                     item.last_interval,
                     item.factor,
                     item.time,
-                    card_type,
+                    kind,
                 ])?;
             }
         }
@@ -2025,9 +2745,9 @@ impl From<i64> for GraveType {
     }
 }
 
-impl Into<i64> for GraveType {
-    fn into(self) -> i64 {
-        match self {
+impl From<GraveType> for i64 {
+    fn from(val: GraveType) -> Self {
+        match val {
             GraveType::Card => 0,
             GraveType::Note => 1,
             GraveType::Deck => 2,
@@ -2043,7 +2763,23 @@ pub struct Grave {
     grave_type: GraveType,
 }
 
+// The object a grave refers to, the type decides which kind of id oid holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraveTarget {
+    Card(CardId),
+    Note(NoteId),
+    Deck(DeckId),
+}
+
 impl Grave {
+    pub fn target(&self) -> GraveTarget {
+        match self.grave_type {
+            GraveType::Card => GraveTarget::Card(self.oid.into()),
+            GraveType::Note => GraveTarget::Note(self.oid.into()),
+            GraveType::Deck => GraveTarget::Deck(self.oid.into()),
+        }
+    }
+
     pub fn save(self, conn: &Connection) -> Result<()> {
         let grave_type: i64 = self.grave_type.into();
         conn.execute(
@@ -2068,6 +2804,7 @@ impl Grave {
 
 // The collection information as stored in the database
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "CollectionData")]
 pub struct Collection {
     id: i64,                       // arbritrary
     crt: i64,                      // creation date in seconds
@@ -2080,11 +2817,202 @@ pub struct Collection {
     models: Vec<Model>,            // JSON, Note types
     decks: Vec<Deck>,              // JSON, the decks
     deck_configs: Vec<DeckConfig>, // JSON, group options for decks
-    tags: String,                  // tag cache
+    tags: BTreeMap<String, i64>,   // JSON, tag registry of tag to usn
     notes: Vec<Note>,              // Notes
     cards: Vec<Card>,              // Cards
     revlog: Vec<ReviewLog>,        // Review log
     graves: Vec<Grave>,            // Deleted things
+    #[serde(skip)]
+    index: Indexes, // Lookups between ids, rebuilt on load
+    #[serde(skip)]
+    removed_notes: Vec<Note>, // Notes removed since loading
+}
+
+// The serialized fields of a collection, the indexes are rebuilt when it is deserialized
+#[derive(Deserialize)]
+struct CollectionData {
+    id: i64,
+    crt: i64,
+    modification_time: i64,
+    schema_time: i64,
+    version: i64,
+    usn: i64,
+    last_sync: i64,
+    config: SyncConfig,
+    models: Vec<Model>,
+    decks: Vec<Deck>,
+    deck_configs: Vec<DeckConfig>,
+    tags: BTreeMap<String, i64>,
+    notes: Vec<Note>,
+    cards: Vec<Card>,
+    revlog: Vec<ReviewLog>,
+    graves: Vec<Grave>,
+}
+
+impl From<CollectionData> for Collection {
+    fn from(data: CollectionData) -> Self {
+        let mut collection = Collection {
+            id: data.id,
+            crt: data.crt,
+            modification_time: data.modification_time,
+            schema_time: data.schema_time,
+            version: data.version,
+            usn: data.usn,
+            last_sync: data.last_sync,
+            config: data.config,
+            models: data.models,
+            decks: data.decks,
+            deck_configs: data.deck_configs,
+            tags: data.tags,
+            notes: data.notes,
+            cards: data.cards,
+            revlog: data.revlog,
+            graves: data.graves,
+            index: Indexes::default(),
+            removed_notes: Vec::new(),
+        };
+        collection.rebuild_indexes();
+        collection
+    }
+}
+
+// Lookup tables from ids into the collection vectors and between related objects
+#[derive(Debug, Clone, Default)]
+struct Indexes {
+    cards: HashMap<CardId, usize>,
+    notes: HashMap<NoteId, usize>,
+    decks: HashMap<DeckId, usize>,
+    models: HashMap<ModelId, usize>,
+    note_cards: HashMap<NoteId, BTreeSet<CardId>>,
+    deck_cards: HashMap<DeckId, BTreeSet<CardId>>,
+}
+
+impl Indexes {
+    fn insert_card(&mut self, card: &Card, pos: usize) {
+        self.cards.insert(card.id, pos);
+        self.note_cards
+            .entry(card.note_id)
+            .or_default()
+            .insert(card.id);
+        self.deck_cards
+            .entry(card.deck_id)
+            .or_default()
+            .insert(card.id);
+    }
+
+    fn remove_card(&mut self, card: &Card) {
+        self.cards.remove(&card.id);
+        if let Some(set) = self.note_cards.get_mut(&card.note_id) {
+            set.remove(&card.id);
+        }
+        if let Some(set) = self.deck_cards.get_mut(&card.deck_id) {
+            set.remove(&card.id);
+        }
+    }
+}
+
+// A card along with the collection it belongs to, for navigating to related objects
+#[derive(Debug, Clone, Copy)]
+pub struct CardRef<'a> {
+    collection: &'a Collection,
+    card: &'a Card,
+}
+
+impl<'a> CardRef<'a> {
+    pub fn note(&self) -> Option<NoteRef<'a>> {
+        self.collection.note(self.card.note_id)
+    }
+
+    pub fn deck(&self) -> Option<DeckRef<'a>> {
+        self.collection.deck(self.card.deck_id)
+    }
+
+    pub fn card(&self) -> &'a Card {
+        self.card
+    }
+}
+
+impl Deref for CardRef<'_> {
+    type Target = Card;
+
+    fn deref(&self) -> &Card {
+        self.card
+    }
+}
+
+// A note along with the collection it belongs to
+#[derive(Debug, Clone, Copy)]
+pub struct NoteRef<'a> {
+    collection: &'a Collection,
+    note: &'a Note,
+}
+
+impl<'a> NoteRef<'a> {
+    pub fn cards(&self) -> impl Iterator<Item = CardRef<'a>> + 'a {
+        let collection = self.collection;
+        collection
+            .index
+            .note_cards
+            .get(&self.note.id)
+            .into_iter()
+            .flatten()
+            .filter_map(move |id| collection.card(*id))
+    }
+
+    pub fn model(&self) -> Option<&'a Model> {
+        self.collection.model(self.note.model_id)
+    }
+
+    pub fn note(&self) -> &'a Note {
+        self.note
+    }
+}
+
+impl Deref for NoteRef<'_> {
+    type Target = Note;
+
+    fn deref(&self) -> &Note {
+        self.note
+    }
+}
+
+// A deck along with the collection it belongs to
+#[derive(Debug, Clone, Copy)]
+pub struct DeckRef<'a> {
+    collection: &'a Collection,
+    deck: &'a Deck,
+}
+
+impl<'a> DeckRef<'a> {
+    pub fn cards(&self) -> impl Iterator<Item = CardRef<'a>> + 'a {
+        let collection = self.collection;
+        collection
+            .index
+            .deck_cards
+            .get(&self.deck.id)
+            .into_iter()
+            .flatten()
+            .filter_map(move |id| collection.card(*id))
+    }
+
+    pub fn config(&self) -> Option<&'a DeckConfig> {
+        self.collection
+            .deck_configs
+            .iter()
+            .find(|c| c.id == self.deck.config_id)
+    }
+
+    pub fn deck(&self) -> &'a Deck {
+        self.deck
+    }
+}
+
+impl Deref for DeckRef<'_> {
+    type Target = Deck;
+
+    fn deref(&self) -> &Deck {
+        self.deck
+    }
 }
 
 impl Collection {
@@ -2102,6 +3030,7 @@ impl Collection {
             let model_txt: String = row.get(8)?;
             let deck_txt: String = row.get(9)?;
             let dconf_txt: String = row.get(10)?;
+            let tags_txt: String = row.get(11)?;
             Ok(Collection {
                 id: row.get(0)?,
                 crt: row.get(1)?,
@@ -2114,11 +3043,13 @@ impl Collection {
                 models: Model::parse(&model_txt).unwrap(),
                 decks: Deck::parse(&deck_txt).unwrap(),
                 deck_configs: DeckConfig::parse(&dconf_txt).unwrap(),
-                tags: row.get(11)?,
+                tags: tags::parse_registry(&tags_txt),
                 notes: Vec::new(),
                 cards: Vec::new(),
                 revlog: Vec::new(),
                 graves: Vec::new(),
+                index: Indexes::default(),
+                removed_notes: Vec::new(),
             })
         })?;
 
@@ -2129,6 +3060,10 @@ impl Collection {
         let card_iter = stmt.query_map([], |row| {
             let card_type: i64 = row.get(6)?;
             let card_queue: i64 = row.get(7)?;
+            let card_type: CardType = card_type.into();
+            let card_queue: CardQueue = card_queue.into();
+            let due: i64 = row.get(8)?;
+            let interval: i64 = row.get(9)?;
             Ok(Card {
                 id: row.get(0)?,
                 note_id: row.get(1)?,
@@ -2136,10 +3071,10 @@ impl Collection {
                 ordinal: row.get(3)?,
                 modification_time: row.get(4)?,
                 usn: row.get(5)?,
-                card_type: card_type.into(),
-                queue: card_queue.into(),
-                due: row.get(8)?,
-                interval: row.get(9)?,
+                due: Due::from_i64(due, card_type, card_queue),
+                interval: interval.into(),
+                card_type,
+                queue: card_queue,
                 factor: row.get(10)?,
                 reps: row.get(11)?,
                 lapses: row.get(12)?,
@@ -2164,8 +3099,8 @@ impl Collection {
                 model_id: row.get(2)?,
                 mod_time: row.get(3)?,
                 usn: row.get(4)?,
-                tags: tags.split(" ").map(String::from).collect(),
-                fields: fields.split("\0x1f").map(String::from).collect(),
+                tags: tags.split_whitespace().map(String::from).collect(),
+                fields: fields.split('\x1f').map(String::from).collect(),
                 sort_field: row.get(7)?,
                 sum: row.get(8)?,
             })
@@ -2173,14 +3108,15 @@ impl Collection {
 
         collection.notes = note_iter.map(|result| result.unwrap()).collect();
 
-        // Load the review log
+        // Load the review log, answers are interpreted by scheduler version
+        let version = collection.scheduler_version();
         let mut stmt = conn
             .prepare("SELECT id, cid, usn, ease, ivl, lastIvl, factor, time, type FROM revlog")?;
         let rev_iter = stmt.query_map([], |row| {
-            let card_type: i64 = row.get(8)?;
+            let kind: i64 = row.get(8)?;
             let revanswer: i64 = row.get(3)?;
-            let card_type: CardType = card_type.into();
-            let revanswer = ReviewAnswer::from_i64(card_type == CardType::Review, revanswer);
+            let kind: RevlogKind = kind.into();
+            let revanswer = ReviewAnswer::from_i64(kind, version, revanswer);
             Ok(ReviewLog {
                 id: row.get(0)?,
                 card_id: row.get(1)?,
@@ -2190,7 +3126,7 @@ impl Collection {
                 last_interval: row.get(5)?,
                 factor: row.get(6)?,
                 time: row.get(7)?,
-                card_type,
+                kind,
             })
         })?;
 
@@ -2209,10 +3145,279 @@ impl Collection {
 
         collection.graves = grave_iter.map(|result| result.unwrap()).collect();
 
+        collection.rebuild_indexes();
+
         Ok(collection)
     }
 
-    pub fn save(self, path: &Path) -> Result<()> {
+    // Creation time of the collection in seconds since epoch
+    pub fn crt(&self) -> i64 {
+        self.crt
+    }
+
+    // Number of days elapsed since the collection was created, what review due dates count in
+    pub fn today(&self) -> i32 {
+        ((now_millis() / 1000 - self.crt) / 86400) as i32
+    }
+
+    // Recompute every id lookup from the collection vectors
+    pub fn rebuild_indexes(&mut self) {
+        let mut index = Indexes::default();
+
+        for (pos, card) in self.cards.iter().enumerate() {
+            index.insert_card(card, pos);
+        }
+        for (pos, note) in self.notes.iter().enumerate() {
+            index.notes.insert(note.id, pos);
+        }
+        for (pos, deck) in self.decks.iter().enumerate() {
+            index.decks.insert(deck.id, pos);
+        }
+        for (pos, model) in self.models.iter().enumerate() {
+            index.models.insert(model.id, pos);
+        }
+
+        self.index = index;
+    }
+
+    pub fn config(&self) -> &SyncConfig {
+        &self.config
+    }
+
+    pub fn scheduler_version(&self) -> SchedulerVersion {
+        self.config.scheduler_version()
+    }
+
+    pub fn config_mut(&mut self) -> &mut SyncConfig {
+        &mut self.config
+    }
+
+    pub fn cards(&self) -> &[Card] {
+        &self.cards
+    }
+
+    // Cards can be edited in place, but not moved between notes or decks (see set_card_deck)
+    pub fn cards_mut(&mut self) -> &mut [Card] {
+        &mut self.cards
+    }
+
+    pub fn card(&self, id: CardId) -> Option<CardRef<'_>> {
+        let pos = *self.index.cards.get(&id)?;
+        Some(CardRef {
+            collection: self,
+            card: &self.cards[pos],
+        })
+    }
+
+    pub fn card_mut(&mut self, id: CardId) -> Option<&mut Card> {
+        let pos = *self.index.cards.get(&id)?;
+        Some(&mut self.cards[pos])
+    }
+
+    pub fn note(&self, id: NoteId) -> Option<NoteRef<'_>> {
+        let pos = *self.index.notes.get(&id)?;
+        Some(NoteRef {
+            collection: self,
+            note: &self.notes[pos],
+        })
+    }
+
+    pub fn note_mut(&mut self, id: NoteId) -> Option<&mut Note> {
+        let pos = *self.index.notes.get(&id)?;
+        Some(&mut self.notes[pos])
+    }
+
+    pub fn deck(&self, id: DeckId) -> Option<DeckRef<'_>> {
+        let pos = *self.index.decks.get(&id)?;
+        Some(DeckRef {
+            collection: self,
+            deck: &self.decks[pos],
+        })
+    }
+
+    pub fn model(&self, id: ModelId) -> Option<&Model> {
+        let pos = *self.index.models.get(&id)?;
+        Some(&self.models[pos])
+    }
+
+    pub fn model_mut(&mut self, id: ModelId) -> Option<&mut Model> {
+        let pos = *self.index.models.get(&id)?;
+        Some(&mut self.models[pos])
+    }
+
+    // Replace a note's fields, updating its sort field and checksum
+    pub fn set_note_fields(&mut self, id: NoteId, fields: Vec<String>) -> bool {
+        let pos = match self.index.notes.get(&id) {
+            Some(&pos) => pos,
+            None => return false,
+        };
+        let sort_index = self
+            .model(self.notes[pos].model_id)
+            .map(|m| m.sort_field())
+            .unwrap_or(0);
+
+        let note = &mut self.notes[pos];
+        let stripped = |i: usize| {
+            fields
+                .get(i)
+                .map(|f| media::strip_html_preserving_media(f))
+                .unwrap_or_default()
+        };
+        note.sort_field = stripped(sort_index);
+        note.sum = field_checksum(&stripped(0));
+        note.fields = fields;
+        note.touch();
+        true
+    }
+
+    // Add a card, replacing any card with the same id
+    pub fn add_card(&mut self, card: Card) {
+        if let Some(&pos) = self.index.cards.get(&card.id) {
+            self.index.remove_card(&self.cards[pos]);
+            self.index.insert_card(&card, pos);
+            self.cards[pos] = card;
+        } else {
+            self.index.insert_card(&card, self.cards.len());
+            self.cards.push(card);
+        }
+    }
+
+    // Add a note, replacing any note with the same id
+    pub fn add_note(&mut self, note: Note) {
+        if let Some(&pos) = self.index.notes.get(&note.id) {
+            self.notes[pos] = note;
+        } else {
+            self.index.notes.insert(note.id, self.notes.len());
+            self.notes.push(note);
+        }
+    }
+
+    // Remove a card and leave a grave for it so the deletion syncs
+    pub fn remove_card(&mut self, id: CardId) -> Option<Card> {
+        let pos = self.index.cards.remove(&id)?;
+        let card = self.cards.swap_remove(pos);
+        self.index.remove_card(&card);
+        if let Some(moved) = self.cards.get(pos) {
+            self.index.cards.insert(moved.id, pos);
+        }
+
+        self.graves.push(Grave {
+            usn: -1,
+            oid: card.id.0,
+            grave_type: GraveType::Card,
+        });
+
+        Some(card)
+    }
+
+    // Remove a note along with all of its cards
+    pub fn remove_note(&mut self, id: NoteId) -> Option<Note> {
+        let pos = self.index.notes.remove(&id)?;
+
+        let card_ids = self.index.note_cards.remove(&id).unwrap_or_default();
+        for card_id in card_ids {
+            self.remove_card(card_id);
+        }
+
+        let note = self.notes.swap_remove(pos);
+        if let Some(moved) = self.notes.get(pos) {
+            self.index.notes.insert(moved.id, pos);
+        }
+
+        self.graves.push(Grave {
+            usn: -1,
+            oid: note.id.0,
+            grave_type: GraveType::Note,
+        });
+        self.removed_notes.push(note.clone());
+
+        Some(note)
+    }
+
+    // Move a card to another deck
+    pub fn set_card_deck(&mut self, id: CardId, deck_id: DeckId) -> bool {
+        let pos = match self.index.cards.get(&id) {
+            Some(&pos) => pos,
+            None => return false,
+        };
+
+        self.index.remove_card(&self.cards[pos]);
+        self.cards[pos].deck_id = deck_id;
+        self.index.insert_card(&self.cards[pos], pos);
+        true
+    }
+
+    pub fn notes(&self) -> &[Note] {
+        &self.notes
+    }
+
+    // Notes removed since the collection was loaded
+    pub fn removed_notes(&self) -> &[Note] {
+        &self.removed_notes
+    }
+
+    pub fn decks(&self) -> &[Deck] {
+        &self.decks
+    }
+
+    pub fn models(&self) -> &[Model] {
+        &self.models
+    }
+
+    pub fn deck_configs(&self) -> &[DeckConfig] {
+        &self.deck_configs
+    }
+
+    pub fn revlog(&self) -> &[ReviewLog] {
+        &self.revlog
+    }
+
+    pub fn add_revlog(&mut self, entry: ReviewLog) {
+        self.revlog.push(entry);
+    }
+
+    // The registered tags along with their usn
+    pub fn tag_registry(&self) -> &BTreeMap<String, i64> {
+        &self.tags
+    }
+
+    pub(crate) fn tag_registry_mut(&mut self) -> &mut BTreeMap<String, i64> {
+        &mut self.tags
+    }
+
+    pub fn notes_mut(&mut self) -> &mut [Note] {
+        &mut self.notes
+    }
+
+    // Id allocation, ids are based on the current time but never collide with existing rows
+    pub fn new_card_id(&self) -> CardId {
+        allocate_id(self.cards.iter().map(|c| c.id.0)).into()
+    }
+
+    pub fn new_note_id(&self) -> NoteId {
+        allocate_id(self.notes.iter().map(|n| n.id.0)).into()
+    }
+
+    pub fn new_deck_id(&self) -> DeckId {
+        allocate_id(self.decks.iter().map(|d| d.id.0)).into()
+    }
+
+    pub fn new_model_id(&self) -> ModelId {
+        allocate_id(self.models.iter().map(|m| m.id.0)).into()
+    }
+
+    pub fn new_deck_config_id(&self) -> DeckConfigId {
+        allocate_id(self.deck_configs.iter().map(|c| c.id.0)).into()
+    }
+
+    pub fn new_revlog_id(&self) -> RevlogId {
+        allocate_id(self.revlog.iter().map(|r| r.id.0)).into()
+    }
+
+    pub fn save(mut self, path: &Path) -> Result<()> {
+        // Keep the tag registry in line with the notes
+        self.rebuild_tag_registry();
+
         // Open the database
         let conn = Connection::open(path)?;
 
@@ -2232,25 +3437,28 @@ impl Collection {
         // Save the collection itself
 
         // Get JSON strings
+        let version = self.scheduler_version();
         let config = self.config.to_json();
         let decks = Deck::to_json_all(self.decks);
         let deck_configs = DeckConfig::to_json_all(self.deck_configs);
         let models = Model::to_json_all(self.models);
+        let tags = tags::registry_to_json(&self.tags);
 
         let config = json::stringify(config);
         let decks = json::stringify(decks);
         let deck_configs = json::stringify(deck_configs);
         let models = json::stringify(models);
+        let tags = json::stringify(tags);
 
         // SQL Query
         conn.execute("INSERT INTO col (id, crt, mod, scm, ver, dty, usn, ls, conf, models, decks, dconf, tags) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
-        params![self.id, self.crt, self.modification_time, self.schema_time, self.version, 0, self.usn, self.last_sync, config, models, decks, deck_configs, self.tags]
+        params![self.id, self.crt, self.modification_time, self.schema_time, self.version, 0, self.usn, self.last_sync, config, models, decks, deck_configs, tags]
             )?;
 
         // Save the other things
         Note::save_all(&conn, self.notes)?;
         Card::save_all(&conn, self.cards)?;
-        ReviewLog::save_all(&conn, self.revlog)?;
+        ReviewLog::save_all(&conn, self.revlog, version)?;
         Grave::save_all(&conn, self.graves)?;
 
         Ok(())
@@ -0,0 +1,453 @@
+/* This file is part of acp.
+ * Copyright (c) 2021 Wyatt Campbell
+ *
+ * See repository LICENSE for information.
+ */
+
+use crate::apkg::Apkg;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Cursor};
+
+// Shrinking the images in a package: dropping metadata, downsizing and re-encoding
+
+// What the optimisation pass may do
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ImageOptions {
+    pub strip_metadata: bool,
+    pub max_dimension: Option<u32>, // longest side in pixels
+    pub convert_to_webp: bool,      // lossless, only kept when smaller
+    pub jpeg_quality: u8,           // used when a resized JPEG is encoded again
+}
+
+impl Default for ImageOptions {
+    fn default() -> Self {
+        ImageOptions {
+            strip_metadata: true,
+            max_dimension: None,
+            convert_to_webp: false,
+            jpeg_quality: 85,
+        }
+    }
+}
+
+// Size of a media file before and after optimisation
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ImageSaving {
+    pub name: String,
+    pub new_name: String,
+    pub old_size: u64,
+    pub new_size: u64,
+}
+
+impl ImageSaving {
+    pub fn saved(&self) -> u64 {
+        self.old_size.saturating_sub(self.new_size)
+    }
+}
+
+// What an optimisation pass did
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct ImageReport {
+    pub savings: Vec<ImageSaving>,
+    pub failed: BTreeMap<String, String>, // images that could not be decoded or encoded, and why
+}
+
+fn image_error(err: image::ImageError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+// CRC-32 as used by PNG chunks
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data.iter() {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+// A TIFF block holding only the orientation tag, all that is kept of the EXIF data
+fn orientation_exif(orientation: Orientation) -> Vec<u8> {
+    let mut exif = b"MM\0\x2a".to_vec();
+    exif.extend_from_slice(&8u32.to_be_bytes()); // Offset of the first IFD
+    exif.extend_from_slice(&1u16.to_be_bytes()); // One entry
+    exif.extend_from_slice(&0x0112u16.to_be_bytes()); // Orientation
+    exif.extend_from_slice(&3u16.to_be_bytes()); // SHORT
+    exif.extend_from_slice(&1u32.to_be_bytes());
+    exif.extend_from_slice(&u16::from(orientation.to_exif()).to_be_bytes());
+    exif.extend_from_slice(&[0, 0]);
+    exif.extend_from_slice(&0u32.to_be_bytes()); // No further IFDs
+    exif
+}
+
+// Drop EXIF, XMP, IPTC and comment segments from a JPEG, keeping colour profiles
+// A rotated image keeps a minimal EXIF segment with just its orientation
+fn strip_jpeg(bytes: &[u8], orientation: Orientation) -> Option<Vec<u8>> {
+    if !bytes.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+
+    let mut out = vec![0xff, 0xd8];
+    if orientation != Orientation::NoTransforms {
+        let mut segment = b"Exif\0\0".to_vec();
+        segment.extend(orientation_exif(orientation));
+        out.extend_from_slice(&[0xff, 0xe1]);
+        out.extend_from_slice(&(segment.len() as u16 + 2).to_be_bytes());
+        out.extend(segment);
+    }
+    let mut pos = 2;
+    while pos + 4 <= bytes.len() {
+        if bytes[pos] != 0xff {
+            return None;
+        }
+        let marker = bytes[pos + 1];
+        // Entropy coded data follows the start of scan, copy everything from here
+        if marker == 0xda {
+            out.extend_from_slice(&bytes[pos..]);
+            return Some(out);
+        }
+        let length = usize::from(u16::from_be_bytes([bytes[pos + 2], bytes[pos + 3]]));
+        let end = pos + 2 + length;
+        if length < 2 || end > bytes.len() {
+            return None;
+        }
+        // APP1 holds EXIF and XMP, APP13 IPTC, and 0xfe is a comment
+        if !matches!(marker, 0xe1 | 0xed | 0xfe) {
+            out.extend_from_slice(&bytes[pos..end]);
+        }
+        pos = end;
+    }
+    None
+}
+
+// Drop text, time and EXIF chunks from a PNG
+// A rotated image keeps a minimal eXIf chunk with just its orientation
+fn strip_png(bytes: &[u8], orientation: Orientation) -> Option<Vec<u8>> {
+    const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+    if !bytes.starts_with(&SIGNATURE) {
+        return None;
+    }
+
+    let mut out = SIGNATURE.to_vec();
+    let mut pos = SIGNATURE.len();
+    while pos + 12 <= bytes.len() {
+        let length = u32::from_be_bytes(bytes[pos..pos + 4].try_into().ok()?) as usize;
+        let end = pos.checked_add(12 + length)?;
+        if end > bytes.len() {
+            return None;
+        }
+        let kind = &bytes[pos + 4..pos + 8];
+        if !matches!(kind, b"tEXt" | b"zTXt" | b"iTXt" | b"eXIf" | b"tIME") {
+            out.extend_from_slice(&bytes[pos..end]);
+        }
+        // eXIf has to come before the image data
+        if kind == b"IHDR" && orientation != Orientation::NoTransforms {
+            let mut chunk = b"eXIf".to_vec();
+            chunk.extend(orientation_exif(orientation));
+            out.extend_from_slice(&(chunk.len() as u32 - 4).to_be_bytes());
+            out.extend_from_slice(&chunk);
+            out.extend_from_slice(&crc32(&chunk).to_be_bytes());
+        }
+        if kind == b"IEND" {
+            return Some(out);
+        }
+        pos = end;
+    }
+    None
+}
+
+// Drop EXIF and XMP chunks from an extended WebP, keeping colour profiles
+// A rotated image keeps a minimal EXIF chunk with just its orientation
+// Simple WebP files have no room for metadata, so there is nothing to strip
+fn strip_webp(bytes: &[u8], orientation: Orientation) -> Option<Vec<u8>> {
+    if bytes.len() < 30 || &bytes[..4] != b"RIFF" || &bytes[8..16] != b"WEBPVP8X" {
+        return None;
+    }
+
+    let mut out = bytes[..12].to_vec();
+    let mut pos = 12;
+    while pos + 8 <= bytes.len() {
+        let length = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into().ok()?) as usize;
+        // Chunks are padded to an even length
+        let end = pos.checked_add(8 + length + length % 2)?.min(bytes.len());
+        if pos + 8 + length > bytes.len() {
+            return None;
+        }
+        if !matches!(&bytes[pos..pos + 4], b"EXIF" | b"XMP ") {
+            out.extend_from_slice(&bytes[pos..end]);
+        }
+        pos = end;
+    }
+
+    // EXIF goes after the image data
+    let mut flags = out[20] & !0x0c;
+    if orientation != Orientation::NoTransforms {
+        let exif = orientation_exif(orientation);
+        out.extend_from_slice(b"EXIF");
+        out.extend_from_slice(&(exif.len() as u32).to_le_bytes());
+        out.extend(exif);
+        flags |= 0x08;
+    }
+    out[20] = flags;
+    let size = u32::try_from(out.len() - 8).ok()?;
+    out[4..8].copy_from_slice(&size.to_le_bytes());
+    Some(out)
+}
+
+fn encode(image: &DynamicImage, format: ImageFormat, quality: u8) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    match format {
+        ImageFormat::Jpeg => {
+            let encoder = JpegEncoder::new_with_quality(&mut out, quality);
+            let rgb = DynamicImage::ImageRgb8(image.to_rgb8());
+            rgb.write_with_encoder(encoder).map_err(image_error)?;
+        }
+        ImageFormat::WebP => {
+            let encoder = WebPEncoder::new_lossless(&mut out);
+            let rgba = match image {
+                DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_) => image.clone(),
+                _ => DynamicImage::ImageRgba8(image.to_rgba8()),
+            };
+            rgba.write_with_encoder(encoder).map_err(image_error)?;
+        }
+        _ => image
+            .write_to(&mut Cursor::new(&mut out), format)
+            .map_err(image_error)?,
+    }
+    Ok(out)
+}
+
+fn with_extension(name: &str, extension: &str) -> String {
+    match name.rfind('.') {
+        Some(i) if i > 0 => format!("{}.{}", &name[..i], extension),
+        _ => format!("{}.{}", name, extension),
+    }
+}
+
+// An optimised image
+struct Optimised {
+    bytes: Vec<u8>,
+    format: ImageFormat,
+    resized: bool,
+}
+
+// Smallest version of an image the options allow
+fn optimise(bytes: &[u8], options: &ImageOptions) -> io::Result<Option<Optimised>> {
+    let reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    let format = match reader.format() {
+        Some(format @ (ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP)) => format,
+        _ => return Ok(None),
+    };
+    let orientation = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .into_decoder()
+        .and_then(|mut decoder| decoder.orientation())
+        .unwrap_or(Orientation::NoTransforms);
+
+    let mut best = bytes.to_vec();
+    if options.strip_metadata {
+        let stripped = match format {
+            ImageFormat::Jpeg => strip_jpeg(bytes, orientation),
+            ImageFormat::Png => strip_png(bytes, orientation),
+            ImageFormat::WebP => strip_webp(bytes, orientation),
+            _ => None,
+        };
+        if let Some(stripped) = stripped {
+            best = stripped;
+        }
+    }
+
+    let too_big = |image: &DynamicImage| match options.max_dimension {
+        Some(max) => image.width().max(image.height()) > max,
+        None => false,
+    };
+    let mut optimised = Optimised {
+        bytes: best,
+        format,
+        resized: false,
+    };
+    if options.max_dimension.is_none() && !options.convert_to_webp {
+        return Ok(Some(optimised));
+    }
+
+    // Encoded images carry no metadata, so turn the pixels the way the orientation says
+    let mut image = reader.decode().map_err(image_error)?;
+    image.apply_orientation(orientation);
+    if too_big(&image) {
+        let max = options.max_dimension.unwrap();
+        image = image.resize(max, max, FilterType::Lanczos3);
+        // Resizing is asked for, so it is kept even if the encoder does worse
+        optimised.bytes = encode(&image, format, options.jpeg_quality)?;
+        optimised.resized = true;
+    }
+
+    if options.convert_to_webp && format != ImageFormat::WebP {
+        let webp = encode(&image, ImageFormat::WebP, options.jpeg_quality)?;
+        if webp.len() < optimised.bytes.len() {
+            optimised.bytes = webp;
+            optimised.format = ImageFormat::WebP;
+        }
+    }
+
+    Ok(Some(optimised))
+}
+
+impl Apkg {
+    // Shrink the images in the package, reporting the files that changed or failed
+    // Files that change format are renamed and every reference to them is updated
+    pub fn optimise_images(&mut self, options: &ImageOptions) -> io::Result<ImageReport> {
+        let names: Vec<String> = self.media().iter().map(|m| m.name().to_string()).collect();
+        let mut report = ImageReport::default();
+        let mut renames = HashMap::new();
+
+        for name in names.into_iter() {
+            let bytes = self.read_media(&name)?;
+            // Template assets may be loaded by scripts we cannot rewrite, so keep their format
+            let mut file_options = options.clone();
+            file_options.convert_to_webp &= !name.starts_with('_');
+
+            let Optimised {
+                bytes: optimised,
+                format,
+                resized,
+            } = match optimise(&bytes, &file_options) {
+                Ok(Some(optimised)) => optimised,
+                // Not an image format that is optimised
+                Ok(None) => continue,
+                Err(err) => {
+                    report.failed.insert(name, err.to_string());
+                    continue;
+                }
+            };
+            // Only keep changes that save space, unless the image had to be downsized
+            if optimised.len() >= bytes.len() && !resized {
+                continue;
+            }
+
+            let new_name = if format == ImageFormat::WebP && !name.to_lowercase().ends_with(".webp")
+            {
+                let new_name = self.add_media(&with_extension(&name, "webp"), &optimised)?;
+                self.remove_media(&name)?;
+                renames.insert(name.clone(), new_name.clone());
+                new_name
+            } else {
+                self.replace_media(&name, &optimised)?;
+                name.clone()
+            };
+
+            report.savings.push(ImageSaving {
+                name,
+                new_name,
+                old_size: bytes.len() as u64,
+                new_size: optimised.len() as u64,
+            });
+        }
+
+        self.collection_mut().rename_media_references(&renames);
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Fixture;
+    use image::RgbImage;
+
+    fn chunk(name: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = name.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    // A 3x2 extended WebP with EXIF saying it is rotated and an XMP packet
+    fn webp_with_metadata() -> Vec<u8> {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(3, 2, [200, 10, 10].into()));
+        let simple = encode(&image, ImageFormat::WebP, 90).unwrap();
+        assert_eq!(&simple[12..16], b"VP8L");
+
+        let mut header = vec![0x0c, 0, 0, 0]; // EXIF and XMP flags
+        header.extend_from_slice(&2u32.to_le_bytes()[..3]); // Width minus one
+        header.extend_from_slice(&1u32.to_le_bytes()[..3]); // Height minus one
+        let mut body = b"WEBP".to_vec();
+        body.extend(chunk(b"VP8X", &header));
+        body.extend_from_slice(&simple[12..]);
+        body.extend(chunk(b"EXIF", &orientation_exif(Orientation::Rotate90)));
+        body.extend(chunk(b"XMP ", b"<x:xmpmeta secret/>"));
+
+        let mut bytes = b"RIFF".to_vec();
+        bytes.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        bytes.extend(body);
+        bytes
+    }
+
+    fn orientation(bytes: &[u8]) -> Orientation {
+        ImageReader::new(Cursor::new(bytes))
+            .with_guessed_format()
+            .unwrap()
+            .into_decoder()
+            .unwrap()
+            .orientation()
+            .unwrap()
+    }
+
+    #[test]
+    fn strip_webp_keeps_only_the_orientation() {
+        let bytes = webp_with_metadata();
+        assert_eq!(orientation(&bytes), Orientation::Rotate90);
+
+        let stripped = strip_webp(&bytes, Orientation::Rotate90).unwrap();
+        assert!(stripped.len() < bytes.len());
+        assert!(!stripped.windows(4).any(|w| w == b"XMP "));
+        assert_eq!(stripped[20], 0x08);
+        assert_eq!(orientation(&stripped), Orientation::Rotate90);
+        let size = u32::from_le_bytes(stripped[4..8].try_into().unwrap()) as usize;
+        assert_eq!(size, stripped.len() - 8);
+        image::load_from_memory(&stripped).unwrap();
+
+        let upright = strip_webp(&bytes, Orientation::NoTransforms).unwrap();
+        assert!(!upright.windows(4).any(|w| w == b"EXIF"));
+        assert_eq!(upright[20], 0);
+        image::load_from_memory(&upright).unwrap();
+    }
+
+    #[test]
+    fn strip_webp_leaves_simple_files() {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(2, 2));
+        let simple = encode(&image, ImageFormat::WebP, 90).unwrap();
+        assert_eq!(strip_webp(&simple, Orientation::NoTransforms), None);
+    }
+
+    #[test]
+    fn failed_images_are_reported() {
+        let fixture = Fixture::new();
+        let mut apkg = fixture.apkg(&["notes.txt"]);
+        let mut broken = b"\x89PNG\r\n\x1a\n".to_vec();
+        broken.extend_from_slice(b"not really an image");
+        apkg.add_media("broken.png", &broken).unwrap();
+        apkg.add_media("photo.webp", &webp_with_metadata()).unwrap();
+
+        let options = ImageOptions {
+            max_dimension: Some(100),
+            ..ImageOptions::default()
+        };
+        let report = apkg.optimise_images(&options).unwrap();
+        assert_eq!(report.failed.keys().collect::<Vec<_>>(), vec!["broken.png"]);
+        assert_eq!(report.savings.len(), 1);
+        assert_eq!(report.savings[0].name, "photo.webp");
+        assert_eq!(apkg.read_media("broken.png").unwrap(), broken);
+    }
+}
@@ -0,0 +1,218 @@
+/* This file is part of acp.
+ * Copyright (c) 2021 Wyatt Campbell
+ *
+ * See repository LICENSE for information.
+ */
+
+use crate::deck::{
+    CardId, CardQueue, Collection, LapsedConfig, LeechAction, NoteId, ReviewAnswer, RevlogId,
+    RevlogKind,
+};
+use crate::tags::tag_matches;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Leech handling as done by Anki's scheduler
+
+pub const LEECH_TAG: &str = "leech";
+
+// A card is a leech when it reaches the threshold, and again every half threshold after that
+pub fn is_leech_lapse(lapses: i64, threshold: i64) -> bool {
+    if threshold <= 0 || lapses < threshold {
+        return false;
+    }
+    let half = (threshold / 2).max(1);
+    (lapses - threshold) % half == 0
+}
+
+// A card which is currently a leech
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Leech {
+    pub card_id: CardId,
+    pub note_id: NoteId,
+    pub lapses: i64,
+    pub threshold: i64,
+    pub suspended: bool,
+    pub tagged: bool,
+}
+
+impl Collection {
+    // The lapse settings from the preset of the card's home deck
+    fn lapse_config(&self, id: CardId) -> Option<LapsedConfig> {
+        let card = self.card(id)?;
+        let deck = match card.original_deck_id().0 {
+            0 => card.deck_id(),
+            _ => card.original_deck_id(),
+        };
+        self.deck(deck)?.config()?.lapse_config().cloned()
+    }
+
+    // Record lapses on a card, returning true if any of them made it a leech
+    pub fn record_lapses(&mut self, id: CardId, count: i64) -> bool {
+        let config = self.lapse_config(id);
+        let card = match self.card_mut(id) {
+            Some(card) => card,
+            None => return false,
+        };
+
+        let before = card.lapses();
+        card.set_lapses(before + count);
+        card.touch();
+
+        let config = match config {
+            Some(config) => config,
+            None => return false,
+        };
+        let leech = (before + 1..=before + count).any(|l| is_leech_lapse(l, config.leech_fails()));
+        if leech {
+            self.apply_leech_action(id, config.leech_action());
+        }
+
+        leech
+    }
+
+    pub fn record_lapse(&mut self, id: CardId) -> bool {
+        self.record_lapses(id, 1)
+    }
+
+    // Bring card lapses up to what the review log shows for cards reviewed after a log id
+    // Used after importing review history for cards, returns the cards that became leeches
+    // Lapses are counted from each card's whole log, so ones the card already has are not
+    // counted twice, and a card never loses lapses its log does not cover
+    pub fn record_revlog_lapses(&mut self, since: RevlogId) -> Vec<CardId> {
+        let mut lapses: HashMap<CardId, i64> = HashMap::new();
+        for entry in self.revlog() {
+            if entry.id() > since {
+                lapses.entry(entry.card_id()).or_default();
+            }
+        }
+        for entry in self.revlog() {
+            if entry.kind() == RevlogKind::Review && entry.ease() == Some(ReviewAnswer::Wrong) {
+                if let Some(count) = lapses.get_mut(&entry.card_id()) {
+                    *count += 1;
+                }
+            }
+        }
+
+        let mut leeches: Vec<CardId> = lapses
+            .into_iter()
+            .filter_map(|(id, count)| {
+                let missing = count - self.card(id)?.lapses();
+                if missing > 0 && self.record_lapses(id, missing) {
+                    Some(id)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        leeches.sort();
+        leeches
+    }
+
+    // Tag the note, and suspend the card if the preset says to
+    fn apply_leech_action(&mut self, id: CardId, action: LeechAction) {
+        let note = match self.card(id) {
+            Some(card) => card.note_id(),
+            None => return,
+        };
+
+        self.add_tags(&[note], LEECH_TAG);
+        if action == LeechAction::Suspend {
+            self.suspend(&[id]);
+        }
+    }
+
+    // Every card whose note is tagged as a leech or whose lapses have reached the threshold
+    pub fn leeches(&self) -> Vec<Leech> {
+        self.cards()
+            .iter()
+            .filter_map(|card| {
+                let threshold = self
+                    .lapse_config(card.id())
+                    .map(|c| c.leech_fails())
+                    .unwrap_or(0);
+                let tagged = self
+                    .note(card.note_id())
+                    .map(|n| n.tags().iter().any(|t| tag_matches(t, LEECH_TAG)))
+                    .unwrap_or(false);
+                let over = threshold > 0 && card.lapses() >= threshold;
+                if !tagged && !over {
+                    return None;
+                }
+
+                Some(Leech {
+                    card_id: card.id(),
+                    note_id: card.note_id(),
+                    lapses: card.lapses(),
+                    threshold,
+                    suspended: card.queue() == CardQueue::Suspended,
+                    tagged,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deck::now_millis;
+    use crate::testing::Fixture;
+
+    #[test]
+    fn leech_at_threshold_and_every_half_threshold() {
+        let leech = |threshold| (0..=20).filter(move |l| is_leech_lapse(*l, threshold));
+        assert_eq!(leech(8).collect::<Vec<_>>(), [8, 12, 16, 20]);
+        assert_eq!(leech(5).collect::<Vec<_>>(), [5, 7, 9, 11, 13, 15, 17, 19]);
+        assert_eq!(leech(1).count(), 20);
+        assert_eq!(leech(0).count(), 0);
+        assert_eq!(leech(-1).count(), 0);
+    }
+
+    // Card 11 has no lapses recorded, card 12 already counts the three in its log,
+    // and card 13 was only reviewed before the import
+    fn imported() -> Collection {
+        let fixture = Fixture::new();
+        fixture
+            .sql(r#"UPDATE col SET dconf = replace(dconf, '"leechFails": 8', '"leechFails": 3')"#)
+            .note(1, "", &["a", "b"])
+            .note(2, "", &["c", "d"])
+            .note(3, "", &["e", "f"])
+            .review_card(11, 1, 1, 110, 10)
+            .review_card(12, 2, 1, 110, 10)
+            .review_card(13, 3, 1, 110, 10)
+            .sql("UPDATE cards SET lapses = 3 WHERE id = 12");
+        for (card, ago) in [(11, 10), (12, 20), (13, 1000)] {
+            fixture
+                .revlog(ago, card, 1, 1)
+                .revlog(ago + 1, card, 1, 1)
+                .revlog(ago + 2, card, 1, 1)
+                // Failing a learning step is not a lapse
+                .revlog(ago + 3, card, 1, 0);
+        }
+        fixture.collection()
+    }
+
+    #[test]
+    fn record_revlog_lapses_counts_lapses_missing_from_cards() {
+        let mut collection = imported();
+        let since = RevlogId(now_millis() - 100 * 1000);
+
+        assert_eq!(collection.record_revlog_lapses(since), [CardId(11)]);
+        let card = collection.card(CardId(11)).unwrap();
+        assert_eq!(card.lapses(), 3);
+        assert_eq!(card.queue(), CardQueue::Suspended);
+        assert!(collection
+            .note(NoteId(1))
+            .unwrap()
+            .tags()
+            .contains(&String::from(LEECH_TAG)));
+
+        assert_eq!(collection.card(CardId(12)).unwrap().lapses(), 3);
+        assert_eq!(collection.card(CardId(13)).unwrap().lapses(), 0);
+
+        // Running it again finds nothing new
+        assert!(collection.record_revlog_lapses(since).is_empty());
+        assert_eq!(collection.card(CardId(11)).unwrap().lapses(), 3);
+    }
+}
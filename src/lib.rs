@@ -5,10 +5,29 @@
  * See repository LICENSE for information.
  */
 
+extern crate base64;
+extern crate image;
 #[macro_use]
 extern crate json;
+extern crate percent_encoding;
+extern crate rand;
+extern crate regex;
 extern crate rusqlite;
 extern crate serde;
+extern crate sha1;
+extern crate unicode_normalization;
 
 pub mod apkg;
+pub mod balance;
 pub mod deck;
+pub mod images;
+pub mod leech;
+pub mod media;
+pub mod migrate;
+pub mod sched;
+pub mod search;
+pub mod simulate;
+pub mod stats;
+pub mod tags;
+#[cfg(test)]
+mod testing;
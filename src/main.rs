@@ -8,6 +8,7 @@ use argparse::{ArgumentParser, Store, StoreTrue};
 #[derive(Debug, Clone)]
 struct Options {
     verbose: bool,
+    backup: bool,
     infile: Option<PathBuf>,
     outfile: Option<PathBuf>,
 }
@@ -16,6 +17,7 @@ impl Options {
     pub fn new() -> Self {
         Options {
             verbose: false,
+            backup: false,
             infile: Option::<PathBuf>::None,
             outfile: Option::<PathBuf>::None,
         }
@@ -27,10 +29,21 @@ fn main() {
 
     println!("{:?}", options);
 
-    let apkg = apkg::Apkg::new(&options.infile.expect("No apkg specified!")).unwrap();
+    let infile = options.infile.expect("No apkg specified!");
+    // "-" reads the apkg from stdin
+    let apkg = if infile.as_os_str() == "-" {
+        apkg::Apkg::from_stream(std::io::stdin().lock()).unwrap()
+    } else {
+        apkg::Apkg::new(&infile).unwrap()
+    };
 
     if let Some(out) = options.outfile {
-        apkg.save(out.as_path()).unwrap();
+        let save_options = apkg::SaveOptions {
+            backup: options.backup,
+            ..Default::default()
+        };
+        apkg.save_with_options(out.as_path(), &save_options)
+            .unwrap();
     }
 }
 
@@ -49,17 +62,19 @@ fn parse_args() -> Options {
                 StoreTrue,
                 "Be verbose",
             );
+            ap.refer(&mut options.backup).add_option(
+                &["-b", "--backup"],
+                StoreTrue,
+                "Keep the previous output file as a .bak",
+            );
             ap.refer(&mut infile)
-                .add_argument("input", Store, "Input apkg file");
+                .add_argument("input", Store, "Input apkg file, - for stdin");
             ap.refer(&mut outfile)
                 .add_option(&["-o", "--output"], Store, "Output apkg file");
             ap.parse_args_or_exit();
         }
 
         if infile != "" {
-            if infile == outfile {
-                panic!("Output file can not be the same as the input file!");
-            }
             options.infile = Some(PathBuf::new().join(&infile));
         }
 
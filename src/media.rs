@@ -0,0 +1,789 @@
+/* This file is part of acp.
+ * Copyright (c) 2021 Wyatt Campbell
+ *
+ * See repository LICENSE for information.
+ */
+
+use crate::apkg::Apkg;
+use crate::deck::{Collection, NoteId};
+use base64::Engine;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::OnceLock;
+use unicode_normalization::UnicodeNormalization;
+
+// Media filename rules, matching what Anki accepts in its media folder, and finding
+// the media that notes, templates and css refer to
+
+// Longest filename Anki allows, in bytes
+pub const MAX_FILENAME_LENGTH: usize = 120;
+
+// Names Windows reserves for devices, regardless of extension
+const WINDOWS_DEVICE_NAMES: [&str; 22] = [
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8",
+    "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+fn is_forbidden(c: char) -> bool {
+    matches!(
+        c,
+        '[' | ']' | '<' | '>' | ':' | '"' | '/' | '?' | '*' | '^' | '\\' | '|'
+    ) || c.is_control()
+}
+
+// Split a filename into its stem and extension, the extension including the dot
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(0) | None => (name, ""),
+        Some(i) => name.split_at(i),
+    }
+}
+
+// Cut a string to at most a number of bytes without splitting a character
+fn truncate_bytes(text: &str, max: usize) -> &str {
+    if text.len() <= max {
+        return text;
+    }
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+// Make a filename safe for every platform Anki runs on
+// NFC normalises, drops forbidden characters, avoids device names and trailing dots or
+// spaces, and shortens long names while keeping the extension
+pub fn normalize_filename(name: &str) -> String {
+    let name: String = name.nfc().filter(|c| !is_forbidden(*c)).collect();
+    let name = name.trim_end_matches(['.', ' ']);
+
+    let (stem, extension) = split_extension(name);
+    let mut stem = String::from(stem);
+    let lower = stem.to_lowercase();
+    if WINDOWS_DEVICE_NAMES.contains(&lower.as_str()) {
+        stem.push('_');
+    }
+
+    let extension = truncate_bytes(extension, MAX_FILENAME_LENGTH / 2);
+    let stem = truncate_bytes(&stem, MAX_FILENAME_LENGTH - extension.len());
+    let stem = stem.trim_end_matches(['.', ' ']);
+
+    if stem.is_empty() {
+        format!("_{}", extension)
+    } else {
+        format!("{}{}", stem, extension)
+    }
+}
+
+// Hex encoded SHA1 of some bytes, as Anki uses to identify media contents
+pub fn sha1_hex(bytes: &[u8]) -> String {
+    let digest = Sha1::digest(bytes);
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Append a hash to the stem of a filename, keeping it within the length limit
+pub fn add_hash_suffix(name: &str, hash: &str) -> String {
+    let (stem, extension) = split_extension(name);
+    let suffix = format!("-{}{}", hash, extension);
+    let stem = truncate_bytes(stem, MAX_FILENAME_LENGTH.saturating_sub(suffix.len()));
+    format!("{}{}", stem, suffix)
+}
+
+// How a reference to a media file is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReferenceKind {
+    Html,  // src attribute of img, audio, video or source, URL encoded
+    Sound, // [sound:name]
+    Css,   // url(name), URL encoded
+}
+
+// A media reference and where its filename sits in the text
+#[derive(Debug, Clone)]
+struct Reference {
+    range: Range<usize>,
+    name: String,
+    kind: ReferenceKind,
+}
+
+fn reference_patterns() -> &'static [(ReferenceKind, Regex); 3] {
+    static PATTERNS: OnceLock<[(ReferenceKind, Regex); 3]> = OnceLock::new();
+    PATTERNS.get_or_init(|| {
+        [
+            (
+                ReferenceKind::Html,
+                Regex::new(
+                    r#"(?i)<(?:img|audio|video|source)\b[^>]*?\bsrc\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s>"']+))"#,
+                )
+                .unwrap(),
+            ),
+            (
+                ReferenceKind::Sound,
+                Regex::new(r"\[sound:([^\]]+)\]").unwrap(),
+            ),
+            (
+                ReferenceKind::Css,
+                Regex::new(r#"(?i)\burl\(\s*(?:"([^"]*)"|'([^']*)'|([^)"'\s]+))\s*\)"#).unwrap(),
+            ),
+        ]
+    })
+}
+
+fn unescape_html(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+// Filename a reference points at, or None for remote files and template fields
+fn decode_reference(raw: &str, kind: ReferenceKind) -> Option<String> {
+    let raw = raw.trim();
+    let lower = raw.to_lowercase();
+    if raw.is_empty()
+        || raw.contains("{{")
+        || lower.contains("://")
+        || lower.starts_with("data:")
+        || lower.starts_with("//")
+    {
+        return None;
+    }
+
+    let unescaped = unescape_html(raw);
+    let name = match kind {
+        ReferenceKind::Sound => unescaped,
+        ReferenceKind::Html | ReferenceKind::Css => percent_decode_str(&unescaped)
+            .decode_utf8_lossy()
+            .into_owned(),
+    };
+    Some(name.nfc().collect())
+}
+
+fn find_references(text: &str) -> Vec<Reference> {
+    let mut references = Vec::new();
+    for (kind, pattern) in reference_patterns().iter() {
+        for captures in pattern.captures_iter(text) {
+            let found = match captures.iter().skip(1).flatten().next() {
+                Some(found) => found,
+                None => continue,
+            };
+            if let Some(name) = decode_reference(found.as_str(), *kind) {
+                references.push(Reference {
+                    range: found.range(),
+                    name,
+                    kind: *kind,
+                });
+            }
+        }
+    }
+    references.sort_by_key(|r| r.range.start);
+    references
+}
+
+// Names of the media files some HTML, template or css text refers to
+pub fn extract_references(text: &str) -> Vec<String> {
+    find_references(text).into_iter().map(|r| r.name).collect()
+}
+
+// Characters left alone when URL encoding a filename
+const FILENAME_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// Write a filename the way the reference it replaces was written
+// URL encoding is kept if the old name used it, and added where the name would otherwise
+// end an unquoted attribute or url()
+fn encode_reference(name: &str, old_raw: &str, quoted: bool, kind: ReferenceKind) -> String {
+    match kind {
+        ReferenceKind::Sound => name
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;"),
+        ReferenceKind::Html | ReferenceKind::Css => {
+            let was_encoded = percent_decode_str(old_raw).decode_utf8_lossy() != old_raw;
+            let needs_encoding = !quoted
+                && name
+                    .chars()
+                    .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '>' | ')'));
+            if was_encoded || needs_encoding {
+                utf8_percent_encode(name, FILENAME_ENCODE_SET).to_string()
+            } else {
+                escape_html(name)
+            }
+        }
+    }
+}
+
+// Rewrite references in some text, returning None if nothing was renamed
+// Renames are keyed by NFC names, as references are normalized when they are read
+pub fn rewrite_references(text: &str, renames: &HashMap<String, String>) -> Option<String> {
+    let mut rewritten = String::with_capacity(text.len());
+    let mut last = 0;
+    for reference in find_references(text).into_iter() {
+        let new = match renames.get(&reference.name) {
+            Some(new) => new,
+            None => continue,
+        };
+        let range = reference.range;
+        let quoted = range.start > 0 && matches!(text.as_bytes()[range.start - 1], b'"' | b'\'');
+        rewritten.push_str(&text[last..range.start]);
+        rewritten.push_str(&encode_reference(
+            new,
+            &text[range.clone()],
+            quoted,
+            reference.kind,
+        ));
+        last = range.end;
+    }
+
+    if last == 0 {
+        return None;
+    }
+    rewritten.push_str(&text[last..]);
+    Some(rewritten)
+}
+
+// Text of a field as the browser's sort column shows it, with image filenames kept
+pub fn strip_html_preserving_media(text: &str) -> String {
+    static PATTERNS: OnceLock<(Regex, Regex)> = OnceLock::new();
+    let (images, tags) = PATTERNS.get_or_init(|| {
+        (
+            Regex::new(r#"(?i)<img[^>]*?\bsrc\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s>]+))[^>]*>"#)
+                .unwrap(),
+            Regex::new(r"(?s)<!--.*?-->|<[^>]*>").unwrap(),
+        )
+    });
+    let text = images.replace_all(text, " ${1}${2}${3} ");
+    let text = tags.replace_all(&text, "");
+    unescape_html(&text).trim().to_string()
+}
+
+// Result of comparing the media in a package against what refers to it
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct MediaCheck {
+    pub missing: BTreeMap<String, Vec<NoteId>>, // notes referring to each, empty for templates
+    pub unused: Vec<String>,                    // never includes _ prefixed files
+    pub deleted_only: Vec<String>,              // unused files that removed notes referred to
+}
+
+// Extension to give a file with a MIME type, for the types notes can show inline
+fn mime_extension(mime: &str) -> Option<&'static str> {
+    match mime.to_lowercase().as_str() {
+        "image/png" => Some("png"),
+        "image/jpeg" | "image/jpg" | "image/pjpeg" => Some("jpg"),
+        "image/gif" => Some("gif"),
+        "image/webp" => Some("webp"),
+        "image/svg+xml" => Some("svg"),
+        "image/bmp" => Some("bmp"),
+        "image/x-icon" | "image/vnd.microsoft.icon" => Some("ico"),
+        "image/avif" => Some("avif"),
+        "image/tiff" => Some("tif"),
+        _ => None,
+    }
+}
+
+// Contents and extension of a data URI, if it is one notes could store as media
+fn decode_data_uri(uri: &str) -> Option<(Vec<u8>, &'static str)> {
+    let uri = unescape_html(uri);
+    let (header, data) = uri.strip_prefix("data:")?.split_once(',')?;
+    let mut parts = header.split(';');
+    let extension = mime_extension(parts.next()?.trim())?;
+
+    let bytes = if parts.any(|p| p.trim().eq_ignore_ascii_case("base64")) {
+        let data: String = data.chars().filter(|c| !c.is_whitespace()).collect();
+        base64::engine::general_purpose::STANDARD
+            .decode(data)
+            .ok()?
+    } else {
+        percent_decode_str(data).collect()
+    };
+    Some((bytes, extension))
+}
+
+// Result of moving inline images out of note fields
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct DataUriExtraction {
+    pub notes: usize,       // notes that were rewritten
+    pub images: usize,      // data URIs replaced
+    pub media: Vec<String>, // media files the images became
+}
+
+// Result of importing a directory of media
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct MediaImport {
+    pub added: Vec<String>,
+    pub replaced: Vec<String>,
+    pub unchanged: usize,
+}
+
+// Result of collapsing duplicate media
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct MediaDedup {
+    pub renamed: BTreeMap<String, String>, // removed duplicate to the name kept
+    pub bytes_saved: u64,
+}
+
+impl Collection {
+    // Media referred to by each note's fields
+    pub fn note_media(&self) -> BTreeMap<String, Vec<NoteId>> {
+        let mut media: BTreeMap<String, Vec<NoteId>> = BTreeMap::new();
+        for note in self.notes().iter() {
+            for field in note.fields().iter() {
+                for name in extract_references(field).into_iter() {
+                    let notes = media.entry(name).or_default();
+                    if notes.last() != Some(&note.id()) {
+                        notes.push(note.id());
+                    }
+                }
+            }
+        }
+        media
+    }
+
+    // Media referred to by the card templates and css of every model
+    pub fn template_media(&self) -> BTreeSet<String> {
+        let mut media = BTreeSet::new();
+        for model in self.models().iter() {
+            media.extend(extract_references(model.css()));
+            for template in model.templates().iter() {
+                let (browser_question, browser_answer) = template.browser_formats();
+                for text in [
+                    template.question_format(),
+                    template.answer_format(),
+                    browser_question,
+                    browser_answer,
+                ] {
+                    media.extend(extract_references(text));
+                }
+            }
+        }
+        media
+    }
+
+    // Rename media in every note, template and css, returning how many notes and models changed
+    pub fn rename_media_references(&mut self, renames: &HashMap<String, String>) -> usize {
+        // References are matched by their NFC names, the names being renamed may not be
+        let renames: HashMap<String, String> = renames
+            .iter()
+            .map(|(old, new)| (old.nfc().collect(), new.clone()))
+            .collect();
+        let renames = &renames;
+        let mut changed = 0;
+
+        let notes: Vec<(NoteId, Vec<String>)> = self
+            .notes()
+            .iter()
+            .filter_map(|note| {
+                let mut renamed = false;
+                let fields = note
+                    .fields()
+                    .iter()
+                    .map(|f| match rewrite_references(f, renames) {
+                        Some(field) => {
+                            renamed = true;
+                            field
+                        }
+                        None => f.clone(),
+                    })
+                    .collect();
+                if renamed {
+                    Some((note.id(), fields))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        for (id, fields) in notes.into_iter() {
+            self.set_note_fields(id, fields);
+            changed += 1;
+        }
+
+        let models: Vec<_> = self.models().iter().map(|m| m.id()).collect();
+        for id in models.into_iter() {
+            let model = self.model_mut(id).unwrap();
+            let mut renamed = false;
+            if let Some(css) = rewrite_references(model.css(), renames) {
+                model.set_css(css);
+                renamed = true;
+            }
+            for template in model.templates_mut().iter_mut() {
+                if let Some(format) = rewrite_references(template.question_format(), renames) {
+                    template.set_question_format(format);
+                    renamed = true;
+                }
+                if let Some(format) = rewrite_references(template.answer_format(), renames) {
+                    template.set_answer_format(format);
+                    renamed = true;
+                }
+                let (question, answer) = template.browser_formats();
+                let question = rewrite_references(question, renames);
+                let answer = rewrite_references(answer, renames);
+                if question.is_some() || answer.is_some() {
+                    let (old_question, old_answer) = template.browser_formats();
+                    let question = question.unwrap_or_else(|| String::from(old_question));
+                    let answer = answer.unwrap_or_else(|| String::from(old_answer));
+                    template.set_browser_formats(question, answer);
+                    renamed = true;
+                }
+            }
+            if renamed {
+                model.touch();
+                changed += 1;
+            }
+        }
+
+        changed
+    }
+}
+
+impl Apkg {
+    // Compare the package's media against the notes, templates and css
+    pub fn check_media(&self) -> MediaCheck {
+        let collection = self.collection();
+        let notes = collection.note_media();
+        let templates = collection.template_media();
+        let deleted: BTreeSet<String> = collection
+            .removed_notes()
+            .iter()
+            .flat_map(|n| n.fields().iter().flat_map(|f| extract_references(f)))
+            .collect();
+        // References are NFC, names in the package may not be (macOS decomposes accents)
+        let present: BTreeMap<String, &str> = self
+            .media()
+            .iter()
+            .map(|m| (m.name().nfc().collect(), m.name()))
+            .collect();
+
+        let mut check = MediaCheck::default();
+        for (name, ids) in notes.iter() {
+            if !present.contains_key(name) {
+                check.missing.insert(name.clone(), ids.clone());
+            }
+        }
+        for name in templates.iter() {
+            if !present.contains_key(name) {
+                check.missing.entry(name.clone()).or_default();
+            }
+        }
+
+        for (normalized, name) in present.into_iter() {
+            let used = name.starts_with('_')
+                || notes.contains_key(&normalized)
+                || templates.contains(&normalized);
+            if used {
+                continue;
+            }
+            if deleted.contains(&normalized) {
+                check.deleted_only.push(String::from(name));
+            }
+            check.unused.push(String::from(name));
+        }
+
+        check
+    }
+
+    // Collapse media with identical contents to one name, rewriting references to the others
+    pub fn dedup_media(&mut self) -> io::Result<MediaDedup> {
+        let mut by_hash: HashMap<String, Vec<(String, u64)>> = HashMap::new();
+        for media in self.media().iter() {
+            let bytes = self.read_media(media.name())?;
+            by_hash
+                .entry(sha1_hex(&bytes))
+                .or_default()
+                .push((String::from(media.name()), bytes.len() as u64));
+        }
+
+        let mut dedup = MediaDedup::default();
+        for (_, mut names) in by_hash.into_iter() {
+            if names.len() < 2 {
+                continue;
+            }
+            // Keep template assets, then the shortest name
+            names.sort_by_key(|(name, _)| (!name.starts_with('_'), name.len(), name.clone()));
+            let canonical = names[0].0.clone();
+            for (name, size) in names.into_iter().skip(1) {
+                dedup.renamed.insert(name, canonical.clone());
+                dedup.bytes_saved += size;
+            }
+        }
+
+        let renames: HashMap<String, String> = dedup
+            .renamed
+            .iter()
+            .map(|(old, new)| (old.clone(), new.clone()))
+            .collect();
+        self.collection_mut().rename_media_references(&renames);
+        for name in dedup.renamed.keys() {
+            self.remove_media(name)?;
+        }
+
+        Ok(dedup)
+    }
+
+    // Move data URI images in note fields into media files named by their hash
+    pub fn extract_data_uris(&mut self) -> io::Result<DataUriExtraction> {
+        static PATTERN: OnceLock<Regex> = OnceLock::new();
+        let pattern = PATTERN.get_or_init(|| {
+            Regex::new(
+                r#"(?i)<img\b[^>]*?\bsrc\s*=\s*(?:"(data:[^"]*)"|'(data:[^']*)'|(data:[^\s>"']+))"#,
+            )
+            .unwrap()
+        });
+
+        let mut extraction = DataUriExtraction::default();
+        let ids: Vec<NoteId> = self.collection().notes().iter().map(|n| n.id()).collect();
+        for id in ids.into_iter() {
+            let fields = match self.collection().note(id) {
+                Some(note) => note.fields().to_vec(),
+                None => continue,
+            };
+
+            let mut changed = false;
+            let mut rewritten = Vec::with_capacity(fields.len());
+            for field in fields.into_iter() {
+                let mut text = String::with_capacity(field.len());
+                let mut last = 0;
+                for captures in pattern.captures_iter(&field) {
+                    let found = captures.iter().skip(1).flatten().next().unwrap();
+                    let (bytes, extension) = match decode_data_uri(found.as_str()) {
+                        Some(decoded) => decoded,
+                        None => continue,
+                    };
+                    let name = format!("{}.{}", sha1_hex(&bytes), extension);
+                    let name = self.add_media(&name, &bytes)?;
+                    if !extraction.media.contains(&name) {
+                        extraction.media.push(name.clone());
+                    }
+
+                    text.push_str(&field[last..found.start()]);
+                    text.push_str(&escape_html(&name));
+                    last = found.end();
+                    extraction.images += 1;
+                }
+                if last > 0 {
+                    text.push_str(&field[last..]);
+                    rewritten.push(text);
+                    changed = true;
+                } else {
+                    rewritten.push(field);
+                }
+            }
+
+            if changed {
+                self.collection_mut().set_note_fields(id, rewritten);
+                extraction.notes += 1;
+            }
+        }
+
+        Ok(extraction)
+    }
+
+    // Write every media file into a directory under its real name, returning how many
+    pub fn export_media(&self, dir: &Path) -> io::Result<usize> {
+        fs::create_dir_all(dir)?;
+        for media in self.media().iter() {
+            // Names are checked on the way in, but packages from elsewhere may hold anything
+            let name = Path::new(media.name());
+            if name.file_name() != Some(name.as_os_str()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("media name {} is not a plain filename", media.name()),
+                ));
+            }
+            fs::write(dir.join(name), self.read_media(media.name())?)?;
+        }
+        Ok(self.media().len())
+    }
+
+    // Add every file in a directory, replacing the contents of media with the same name
+    // Subdirectories and hidden files are skipped
+    pub fn import_media_dir(&mut self, dir: &Path) -> io::Result<MediaImport> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type()?.is_file() && !name.starts_with('.') {
+                entries.push((name, entry.path()));
+            }
+        }
+        entries.sort();
+
+        let mut import = MediaImport::default();
+        for (name, path) in entries.into_iter() {
+            let bytes = fs::read(path)?;
+            match self.read_media(&name) {
+                Ok(existing) if existing == bytes => import.unchanged += 1,
+                Ok(_) => {
+                    self.replace_media(&name, &bytes)?;
+                    import.replaced.push(normalize_filename(&name));
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    import.added.push(self.add_media(&name, &bytes)?)
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(import)
+    }
+
+    // Remove media nothing refers to, returning the removed names
+    pub fn prune_unused_media(&mut self) -> io::Result<Vec<String>> {
+        let unused = self.check_media().unused;
+        for name in unused.iter() {
+            self.remove_media(name)?;
+        }
+        Ok(unused)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Fixture;
+
+    fn rename(text: &str, old: &str, new: &str) -> Option<String> {
+        let renames = HashMap::from([(String::from(old), String::from(new))]);
+        rewrite_references(text, &renames)
+    }
+
+    #[test]
+    fn encode_quoted_names_as_html() {
+        let encode = |name| encode_reference(name, "old.png", true, ReferenceKind::Html);
+        assert_eq!(encode("a b.png"), "a b.png");
+        assert_eq!(encode("ü.png"), "ü.png");
+        assert_eq!(encode("a&b\".png"), "a&amp;b&quot;.png");
+    }
+
+    #[test]
+    fn encode_unquoted_names_when_they_would_end_the_value() {
+        let encode = |name, kind| encode_reference(name, "old.png", false, kind);
+        assert_eq!(encode("a.png", ReferenceKind::Html), "a.png");
+        assert_eq!(encode("a b.png", ReferenceKind::Html), "a%20b.png");
+        assert_eq!(encode("a>b.png", ReferenceKind::Html), "a%3Eb.png");
+        assert_eq!(encode("a(1).png", ReferenceKind::Css), "a%281%29.png");
+    }
+
+    #[test]
+    fn encode_keeps_percent_encoding_of_the_old_reference() {
+        let encode =
+            |name, quoted| encode_reference(name, "old%20name.png", quoted, ReferenceKind::Html);
+        assert_eq!(encode("a b.png", true), "a%20b.png");
+        assert_eq!(encode("ü.png", true), "%C3%BC.png");
+        assert_eq!(encode("a-b_c~d.png", false), "a-b_c~d.png");
+    }
+
+    #[test]
+    fn encode_sound_references_as_html_text() {
+        let encode = |name| encode_reference(name, "old.mp3", false, ReferenceKind::Sound);
+        assert_eq!(encode("a b.mp3"), "a b.mp3");
+        assert_eq!(encode("<a&b>.mp3"), "&lt;a&amp;b&gt;.mp3");
+    }
+
+    #[test]
+    fn rewrite_quoted_and_unquoted_references() {
+        assert_eq!(
+            rename(r#"<img src="a.png">"#, "a.png", "b c.png").unwrap(),
+            r#"<img src="b c.png">"#
+        );
+        assert_eq!(
+            rename("<img src='a.png'>", "a.png", "b c.png").unwrap(),
+            "<img src='b c.png'>"
+        );
+        assert_eq!(
+            rename("<img src=a.png>", "a.png", "b c.png").unwrap(),
+            "<img src=b%20c.png>"
+        );
+        assert_eq!(
+            rename("x url(a.png) y", "a.png", "b (1).png").unwrap(),
+            "x url(b%20%281%29.png) y"
+        );
+        assert_eq!(
+            rename("[sound:a.mp3] [sound:b.mp3]", "a.mp3", "c.mp3").unwrap(),
+            "[sound:c.mp3] [sound:b.mp3]"
+        );
+    }
+
+    #[test]
+    fn rewrite_percent_encoded_references() {
+        assert_eq!(
+            rename(r#"<img src="a%20b.png">"#, "a b.png", "c d.png").unwrap(),
+            r#"<img src="c%20d.png">"#
+        );
+        assert_eq!(
+            rename(r#"<img src="%C3%BC.png">"#, "ü.png", "ö.png").unwrap(),
+            r#"<img src="%C3%B6.png">"#
+        );
+        assert_eq!(
+            rename(r#"<img src="a&amp;b.png">"#, "a&b.png", "c&d.png").unwrap(),
+            r#"<img src="c&amp;d.png">"#
+        );
+    }
+
+    #[test]
+    fn rewrite_every_reference_to_a_renamed_file() {
+        let text = r#"<img src="a.png"><audio src="x.mp3"></audio><img src=a.png>"#;
+        assert_eq!(
+            rename(text, "a.png", "b.png").unwrap(),
+            r#"<img src="b.png"><audio src="x.mp3"></audio><img src=b.png>"#
+        );
+    }
+
+    #[test]
+    fn rewrite_nothing_when_no_reference_is_renamed() {
+        assert_eq!(rename(r#"<img src="a.png">"#, "b.png", "c.png"), None);
+        assert_eq!(rename("a.png", "a.png", "c.png"), None);
+        assert_eq!(
+            rename(r#"<img src="http://x/a.png">"#, "a.png", "c.png"),
+            None
+        );
+        assert_eq!(rename(r#"<img src="{{a.png}}">"#, "a.png", "c.png"), None);
+    }
+
+    // "café.png" with the accent as a combining character, as macOS writes it
+    const DECOMPOSED: &str = "cafe\u{301}.png";
+    const COMPOSED: &str = "caf\u{e9}.png";
+
+    #[test]
+    fn check_media_matches_names_that_are_not_nfc() {
+        let fixture = Fixture::new();
+        fixture.note(1, "", &["<img src=\"caf\u{e9}.png\">", ""]);
+        let apkg = fixture.apkg(&[DECOMPOSED, "other.png"]);
+
+        let check = apkg.check_media();
+        assert!(check.missing.is_empty());
+        assert_eq!(check.unused, ["other.png"]);
+        assert_eq!(apkg.read_media(COMPOSED).unwrap(), DECOMPOSED.as_bytes());
+    }
+
+    #[test]
+    fn rename_references_from_names_that_are_not_nfc() {
+        let fixture = Fixture::new();
+        fixture.note(1, "", &["<img src=\"caf\u{e9}.png\">", ""]);
+        let mut collection = fixture.collection();
+
+        let renames = HashMap::from([(String::from(DECOMPOSED), String::from("tea.png"))]);
+        assert_eq!(collection.rename_media_references(&renames), 1);
+        let note = collection.note(NoteId(1)).unwrap();
+        assert_eq!(note.fields()[0], r#"<img src="tea.png">"#);
+    }
+}
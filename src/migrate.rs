@@ -0,0 +1,189 @@
+/* This file is part of acp.
+ * Copyright (c) 2021 Wyatt Campbell
+ *
+ * See repository LICENSE for information.
+ */
+
+use crate::deck::{
+    CardId, CardQueue, CardType, Collection, DeckId, Due, Interval, ReviewAnswer, SchedulerVersion,
+};
+use crate::sched::SchedError;
+use serde::{Deserialize, Serialize};
+
+// Converting card state between the v1 scheduler and the v2/v3 schedulers
+// The v2 and v3 schedulers share card state, only the v1 scheduler differs:
+// - relearning cards have card type review instead of relearning
+// - there is no separate queue for manually buried cards, or for previews
+// - learning cards show three answer buttons instead of four
+
+// What a scheduler change did, or would do on a dry run
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SchedulerChange {
+    pub from: SchedulerVersion,
+    pub to: SchedulerVersion,
+    pub returned_from_filtered: Vec<CardId>, // moved back to their home deck
+    pub retyped: Vec<CardId>,                // card type changed between review and relearning
+    pub requeued: Vec<CardId>,               // queue or due changed
+    pub reset_to_new: Vec<CardId>,           // learning cards v1 can not represent
+    pub remapped_revlog: usize,              // entries whose stored ease value changes
+}
+
+fn is_learning_queue(queue: CardQueue) -> bool {
+    matches!(queue, CardQueue::Learning | CardQueue::InLearning)
+}
+
+impl Collection {
+    // Switch the collection to another scheduler, converting card state and answer values
+    // On a dry run the collection is left alone and the report says what would change
+    pub fn change_scheduler(
+        &mut self,
+        to: SchedulerVersion,
+        dry_run: bool,
+    ) -> Result<SchedulerChange, SchedError> {
+        if dry_run {
+            return self.clone().change_scheduler(to, false);
+        }
+
+        let from = self.scheduler_version();
+        let mut change = SchedulerChange {
+            from,
+            to,
+            returned_from_filtered: Vec::new(),
+            retyped: Vec::new(),
+            requeued: Vec::new(),
+            reset_to_new: Vec::new(),
+            remapped_revlog: 0,
+        };
+
+        if from == SchedulerVersion::V1 && to != SchedulerVersion::V1 {
+            self.upgrade_cards(&mut change);
+        } else if from != SchedulerVersion::V1 && to == SchedulerVersion::V1 {
+            // Cards may be reset to new, check there are positions for them before changing any
+            self.next_positions(self.cards().len())?;
+            self.downgrade_cards(&mut change)?;
+        }
+
+        // Answers are stored by meaning, so saving with the new version remaps them
+        change.remapped_revlog = self
+            .revlog()
+            .iter()
+            .filter(|r| {
+                ReviewAnswer::into_i64(r.ease(), r.kind(), from)
+                    != ReviewAnswer::into_i64(r.ease(), r.kind(), to)
+            })
+            .count();
+
+        self.config_mut().set_scheduler_version(to);
+        Ok(change)
+    }
+
+    // Filtered decks handle learning cards differently, so send those cards home
+    fn return_learning_from_filtered(&mut self, change: &mut SchedulerChange) {
+        let ids: Vec<CardId> = self
+            .cards()
+            .iter()
+            .filter(|c| c.original_deck_id() != DeckId(0))
+            .filter(|c| is_learning_queue(c.queue()) || c.queue() == CardQueue::Preview)
+            .map(|c| c.id())
+            .collect();
+
+        for id in ids.into_iter() {
+            self.return_from_filtered(id);
+            if let Some(card) = self.card_mut(id) {
+                if card.queue() == CardQueue::Preview {
+                    card.set_queue(card.natural_queue());
+                }
+                card.touch();
+            }
+            change.returned_from_filtered.push(id);
+        }
+    }
+
+    fn upgrade_cards(&mut self, change: &mut SchedulerChange) {
+        self.return_learning_from_filtered(change);
+
+        for card in self.cards_mut().iter_mut() {
+            let mut changed = false;
+
+            // Relearning cards get their own type
+            if card.card_type() == CardType::Review && is_learning_queue(card.queue()) {
+                card.set_card_type(CardType::Relearning);
+                change.retyped.push(card.id());
+                changed = true;
+            }
+
+            // Learning cards due on a day belong in the day learning queue
+            if let (CardQueue::Learning, Due::Timestamp(due)) = (card.queue(), card.due()) {
+                if due < 1_000_000_000 {
+                    card.set_queue(CardQueue::InLearning);
+                    card.set_due(Due::Day(due as i32));
+                    change.requeued.push(card.id());
+                    changed = true;
+                }
+            }
+
+            // v1 only had one kind of burying, which was done by hand
+            if card.queue() == CardQueue::Buried {
+                card.set_queue(CardQueue::UserBuried);
+                change.requeued.push(card.id());
+                changed = true;
+            }
+
+            if changed {
+                card.touch();
+            }
+        }
+    }
+
+    fn downgrade_cards(&mut self, change: &mut SchedulerChange) -> Result<(), SchedError> {
+        self.return_learning_from_filtered(change);
+
+        // v1 can not continue v2 learning steps, so cards leave learning as Anki does
+        let today = self.today();
+        let mut forget = Vec::new();
+        for card in self.cards_mut().iter_mut() {
+            let mut changed = false;
+
+            if is_learning_queue(card.queue()) {
+                match card.card_type() {
+                    CardType::Review | CardType::Relearning => {
+                        let interval = match card.interval() {
+                            Interval::Days(days) => days.max(1),
+                            Interval::Seconds(_) => 1,
+                        };
+                        if card.card_type() == CardType::Relearning {
+                            change.retyped.push(card.id());
+                        }
+                        card.set_card_type(CardType::Review);
+                        card.set_queue(CardQueue::Review);
+                        card.set_due(Due::Day(today));
+                        card.set_interval(Interval::Days(interval));
+                        card.set_left(0);
+                        change.requeued.push(card.id());
+                        changed = true;
+                    }
+                    _ => forget.push(card.id()),
+                }
+            } else if card.card_type() == CardType::Relearning {
+                // Suspended or buried relearning cards
+                card.set_card_type(CardType::Review);
+                change.retyped.push(card.id());
+                changed = true;
+            }
+
+            if card.queue() == CardQueue::UserBuried {
+                card.set_queue(CardQueue::Buried);
+                change.requeued.push(card.id());
+                changed = true;
+            }
+
+            if changed {
+                card.touch();
+            }
+        }
+
+        self.forget(&forget, false)?;
+        change.reset_to_new = forget;
+        Ok(())
+    }
+}
@@ -0,0 +1,666 @@
+/* This file is part of acp.
+ * Copyright (c) 2021 Wyatt Campbell
+ *
+ * See repository LICENSE for information.
+ */
+
+use crate::deck::{
+    Card, CardId, CardQueue, CardType, Collection, DeckId, Due, Interval, NewOrder, NoteId,
+    ReviewLog, RevlogId,
+};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+// Card scheduling operations that Anki offers from the browser
+
+// Whether a card was buried by hand or because a sibling was answered
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum BuryMode {
+    User,
+    Sibling,
+}
+
+// The colored flag on a card, stored in the low 3 bits of the card flags
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Flag {
+    None,
+    Red,
+    Orange,
+    Green,
+    Blue,
+    Pink,
+    Turquoise,
+    Purple,
+}
+
+impl From<i64> for Flag {
+    fn from(i: i64) -> Self {
+        match i & 0b111 {
+            1 => Flag::Red,
+            2 => Flag::Orange,
+            3 => Flag::Green,
+            4 => Flag::Blue,
+            5 => Flag::Pink,
+            6 => Flag::Turquoise,
+            7 => Flag::Purple,
+            _ => Flag::None,
+        }
+    }
+}
+
+impl From<Flag> for i64 {
+    fn from(val: Flag) -> Self {
+        match val {
+            Flag::None => 0,
+            Flag::Red => 1,
+            Flag::Orange => 2,
+            Flag::Green => 3,
+            Flag::Blue => 4,
+            Flag::Pink => 5,
+            Flag::Turquoise => 6,
+            Flag::Purple => 7,
+        }
+    }
+}
+
+// Errors from scheduling operations
+#[derive(Debug, Clone, PartialEq)]
+pub enum SchedError {
+    InvalidDueDate(String),
+    PositionOutOfRange, // Repositioning would go past the largest position
+    InvalidEase(i64),   // Below the lowest ease Anki allows, in permille
+}
+
+impl fmt::Display for SchedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchedError::InvalidDueDate(spec) => write!(f, "invalid due date '{}'", spec),
+            SchedError::PositionOutOfRange => write!(f, "new card position out of range"),
+            SchedError::InvalidEase(factor) => {
+                write!(
+                    f,
+                    "ease {}% is below the {}% minimum",
+                    factor / 10,
+                    MIN_EASE / 10
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for SchedError {}
+
+// Lowest ease Anki lets a card have, in permille
+const MIN_EASE: i64 = 1300;
+
+// Furthest a due date can be set, the same cap as the largest interval Anki allows
+const MAX_DUE_DAYS: u32 = 36500;
+
+// Input to "Set Due Date": days from today as "0", "3-7" or "10!", "!" also resets the interval
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DueDateSpec {
+    pub min: u32,
+    pub max: u32,
+    pub reset_interval: bool,
+}
+
+impl FromStr for DueDateSpec {
+    type Err = SchedError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || SchedError::InvalidDueDate(String::from(s));
+
+        let text = s.trim();
+        let (text, reset_interval) = match text.strip_suffix('!') {
+            Some(text) => (text.trim(), true),
+            None => (text, false),
+        };
+
+        let (min, max) = match text.split_once('-') {
+            Some((min, max)) => (min.trim(), max.trim()),
+            None => (text, text),
+        };
+        let min = min.parse::<u32>().map_err(|_| invalid())?;
+        let max = max.parse::<u32>().map_err(|_| invalid())?;
+        if min > max || max > MAX_DUE_DAYS {
+            return Err(invalid());
+        }
+
+        Ok(DueDateSpec {
+            min,
+            max,
+            reset_interval,
+        })
+    }
+}
+
+fn is_buried(queue: CardQueue) -> bool {
+    matches!(queue, CardQueue::Buried | CardQueue::UserBuried)
+}
+
+impl Collection {
+    // Apply an edit to each card, touching the ones it reports as changed
+    pub(crate) fn update_cards<F>(&mut self, cards: &[CardId], mut edit: F) -> usize
+    where
+        F: FnMut(&mut Card) -> bool,
+    {
+        let mut changed = 0;
+        for id in cards.iter() {
+            if let Some(card) = self.card_mut(*id) {
+                if edit(card) {
+                    card.touch();
+                    changed += 1;
+                }
+            }
+        }
+        changed
+    }
+
+    // Suspend cards, returning how many changed
+    pub fn suspend(&mut self, cards: &[CardId]) -> usize {
+        self.update_cards(cards, |card| {
+            if card.queue() == CardQueue::Suspended {
+                return false;
+            }
+            card.set_queue(CardQueue::Suspended);
+            true
+        })
+    }
+
+    // Move suspended cards back into the queue for their type
+    pub fn unsuspend(&mut self, cards: &[CardId]) -> usize {
+        self.update_cards(cards, |card| {
+            if card.queue() != CardQueue::Suspended {
+                return false;
+            }
+            card.set_queue(card.natural_queue());
+            true
+        })
+    }
+
+    // Bury cards until the next day, suspended cards are left alone
+    pub fn bury(&mut self, cards: &[CardId], mode: BuryMode) -> usize {
+        let queue = match mode {
+            BuryMode::User => CardQueue::UserBuried,
+            BuryMode::Sibling => CardQueue::Buried,
+        };
+
+        self.update_cards(cards, |card| {
+            if card.queue() == queue || card.queue() == CardQueue::Suspended {
+                return false;
+            }
+            card.set_queue(queue);
+            true
+        })
+    }
+
+    // Move buried cards back into the queue for their type
+    pub fn unbury(&mut self, cards: &[CardId]) -> usize {
+        self.update_cards(cards, |card| {
+            if !is_buried(card.queue()) {
+                return false;
+            }
+            card.set_queue(card.natural_queue());
+            true
+        })
+    }
+
+    // Unbury everything once per day, as Anki does when the day rolls over
+    pub fn unbury_all_for_day(&mut self) -> usize {
+        let today = i64::from(self.today());
+        if let Some(last) = self.config().last_unburied() {
+            if last >= today {
+                return 0;
+            }
+        }
+
+        let buried: Vec<CardId> = self
+            .cards()
+            .iter()
+            .filter(|c| is_buried(c.queue()))
+            .map(|c| c.id())
+            .collect();
+        let changed = self.unbury(&buried);
+
+        self.config_mut().set_last_unburied(today);
+        changed
+    }
+
+    // Set the flag on cards, leaving the other flag bits alone
+    pub fn set_flag(&mut self, cards: &[CardId], flag: Flag) -> usize {
+        let flag: i64 = flag.into();
+        self.update_cards(cards, |card| {
+            let flags = (card.flags() & !0b111) | flag;
+            if flags == card.flags() {
+                return false;
+            }
+            card.set_flags(flags);
+            true
+        })
+    }
+
+    // Put a card in a filtered deck back in its home deck with its original due
+    pub(crate) fn return_from_filtered(&mut self, id: CardId) {
+        let home = match self.card(id) {
+            Some(card) if card.original_deck_id() != DeckId(0) => card.original_deck_id(),
+            _ => return,
+        };
+
+        self.set_card_deck(id, home);
+        if let Some(card) = self.card_mut(id) {
+            card.set_original_deck_id(DeckId(0));
+            if card.original_due() != 0 {
+                let due = Due::from_i64(card.original_due(), card.card_type(), card.queue());
+                card.set_due(due);
+                card.set_original_due(0);
+            }
+        }
+    }
+
+    // Starting ease factor from the preset of the card's deck
+    pub(crate) fn initial_factor(&self, id: CardId) -> i64 {
+        self.card(id)
+            .and_then(|c| c.deck())
+            .and_then(|d| d.config())
+            .and_then(|c| c.new_config())
+            .map(|n| n.initial_factor())
+            .unwrap_or(2500)
+    }
+
+    // Longest interval the preset of a card's deck allows, in days
+    fn max_interval(&self, id: CardId) -> f64 {
+        self.card(id)
+            .and_then(|c| c.deck())
+            .and_then(|d| d.config())
+            .and_then(|c| c.review_config())
+            .map(|r| r.max_interval())
+            .unwrap_or(f64::from(MAX_DUE_DAYS))
+    }
+
+    // Log manual entries for cards whose previous interval is given
+    pub(crate) fn log_manual(&mut self, changed: Vec<(CardId, Interval)>) {
+        let mut next_id = self.new_revlog_id().0;
+        for (id, last_interval) in changed.into_iter() {
+            let entry = match self.card(id) {
+                Some(card) => ReviewLog::manual(RevlogId(next_id), &card, last_interval),
+                None => continue,
+            };
+            self.add_revlog(entry);
+            next_id += 1;
+        }
+    }
+
+    // First of a number of new card positions taken from the collection's next position
+    pub(crate) fn next_positions(&self, count: usize) -> Result<u32, SchedError> {
+        let next_pos = self.config().next_pos();
+        let end = i64::try_from(count)
+            .ok()
+            .and_then(|count| next_pos.checked_add(count));
+        match (u32::try_from(next_pos), end.map(u32::try_from)) {
+            (Ok(first), Some(Ok(_))) => Ok(first),
+            _ => Err(SchedError::PositionOutOfRange),
+        }
+    }
+
+    // Reset cards to new at the end of the new queue, optionally clearing reps and lapses
+    pub fn forget(&mut self, cards: &[CardId], reset_counts: bool) -> Result<usize, SchedError> {
+        let mut next_pos = self.next_positions(cards.len())?;
+        let mut changed = Vec::new();
+
+        for id in cards.iter() {
+            let is_new = match self.card(*id) {
+                Some(card) => card.card_type() == CardType::New,
+                None => continue,
+            };
+            if is_new && !reset_counts {
+                continue;
+            }
+
+            self.return_from_filtered(*id);
+            let card = self.card_mut(*id).unwrap();
+            let last_interval = card.interval();
+            if !is_new {
+                card.set_card_type(CardType::New);
+                card.set_queue(CardQueue::New);
+                card.set_due(Due::Position(next_pos));
+                card.set_interval(Interval::Days(0));
+                card.set_factor(0);
+                card.set_left(0);
+                next_pos += 1;
+            }
+            if reset_counts {
+                card.set_reps(0);
+                card.set_lapses(0);
+            }
+            card.touch();
+            changed.push((*id, last_interval));
+        }
+
+        self.config_mut().set_next_pos(i64::from(next_pos));
+        let count = changed.len();
+        self.log_manual(changed);
+        Ok(count)
+    }
+
+    // Give new cards positions start, start + step, ... with siblings sharing a position
+    // Randomizing follows the deck's new card order when not given
+    pub fn reposition(
+        &mut self,
+        cards: &[CardId],
+        start: u32,
+        step: u32,
+        randomize: Option<bool>,
+        shift: bool,
+    ) -> Result<usize, SchedError> {
+        // Only new cards have a position, order the notes by their current one
+        let mut notes: Vec<(u32, NoteId)> = Vec::new();
+        let mut selected = HashSet::new();
+        for id in cards.iter() {
+            if let Some(card) = self.card(*id) {
+                if card.card_type() != CardType::New {
+                    continue;
+                }
+                let pos = match card.due() {
+                    Due::Position(pos) => pos,
+                    _ => u32::MAX,
+                };
+                notes.push((pos, card.note_id()));
+                selected.insert(*id);
+            }
+        }
+        if selected.is_empty() {
+            return Ok(0);
+        }
+
+        notes.sort();
+        let mut seen = HashSet::new();
+        let mut notes: Vec<NoteId> = notes
+            .into_iter()
+            .map(|(_, nid)| nid)
+            .filter(|nid| seen.insert(*nid))
+            .collect();
+
+        let randomize = randomize.unwrap_or_else(|| {
+            self.card(*selected.iter().next().unwrap())
+                .and_then(|c| c.deck())
+                .and_then(|d| d.config())
+                .and_then(|c| c.new_config())
+                .map(|n| n.order() == NewOrder::Random)
+                .unwrap_or(false)
+        });
+        if randomize {
+            notes.shuffle(&mut rand::thread_rng());
+        }
+
+        // Check every position fits before changing anything
+        let out_of_range = || SchedError::PositionOutOfRange;
+        let count = u32::try_from(notes.len()).map_err(|_| out_of_range())?;
+        let last = step
+            .checked_mul(count - 1)
+            .and_then(|offset| start.checked_add(offset))
+            .ok_or_else(out_of_range)?;
+        let by = step.checked_mul(count).ok_or_else(out_of_range)?;
+        if shift {
+            let furthest = self
+                .cards()
+                .iter()
+                .filter(|c| !selected.contains(&c.id()) && c.card_type() == CardType::New)
+                .filter_map(|c| match c.due() {
+                    Due::Position(pos) if pos >= start => Some(pos),
+                    _ => None,
+                })
+                .max();
+            if let Some(pos) = furthest {
+                pos.checked_add(by).ok_or_else(out_of_range)?;
+            }
+        }
+
+        let positions: HashMap<NoteId, u32> = notes
+            .iter()
+            .zip(0..)
+            .map(|(nid, i)| (*nid, start + i * step))
+            .collect();
+
+        // Move the other new cards out of the way
+        if shift {
+            for card in self.cards_mut().iter_mut() {
+                if selected.contains(&card.id()) || card.card_type() != CardType::New {
+                    continue;
+                }
+                if let Due::Position(pos) = card.due() {
+                    if pos >= start {
+                        card.set_due(Due::Position(pos + by));
+                        card.touch();
+                    }
+                }
+            }
+        }
+
+        let selected: Vec<CardId> = selected.into_iter().collect();
+        let changed = self.update_cards(&selected, |card| {
+            card.set_due(Due::Position(positions[&card.note_id()]));
+            true
+        });
+
+        let next_pos = self.config().next_pos().max(i64::from(last) + 1);
+        self.config_mut().set_next_pos(next_pos);
+
+        Ok(changed)
+    }
+
+    // Make cards review cards due a number of days from today, logging a manual entry for each
+    pub fn set_due_date(&mut self, cards: &[CardId], spec: &str) -> Result<usize, SchedError> {
+        let text = spec;
+        let spec: DueDateSpec = spec.parse()?;
+        let today = self.today();
+        if today.checked_add(spec.max as i32).is_none() {
+            return Err(SchedError::InvalidDueDate(String::from(text)));
+        }
+        let mut rng = rand::thread_rng();
+        let mut changed = Vec::new();
+
+        for id in cards.iter() {
+            if self.card(*id).is_none() {
+                continue;
+            }
+            self.return_from_filtered(*id);
+            let initial_factor = self.initial_factor(*id);
+
+            let card = self.card_mut(*id).unwrap();
+            let last_interval = card.interval();
+            let days = rng.gen_range(spec.min..=spec.max);
+
+            let is_review = matches!(card.card_type(), CardType::Review | CardType::Relearning);
+            if spec.reset_interval || !is_review {
+                card.set_interval(Interval::Days(days.max(1)));
+            }
+            if card.factor() == 0 {
+                card.set_factor(initial_factor);
+            }
+            card.set_card_type(CardType::Review);
+            card.set_queue(CardQueue::Review);
+            card.set_due(Due::Day(today + days as i32));
+            card.set_left(0);
+            card.touch();
+            changed.push((*id, last_interval));
+        }
+
+        let count = changed.len();
+        self.log_manual(changed);
+        Ok(count)
+    }
+
+    // Reset the ease of review cards to a given factor or their preset's starting ease,
+    // optionally scaling intervals by the same ratio, returning how many cards changed
+    // Scaled intervals stay within the preset's maximum interval
+    pub fn reset_ease(
+        &mut self,
+        cards: &[CardId],
+        factor: Option<i64>,
+        scale_intervals: bool,
+    ) -> Result<usize, SchedError> {
+        if let Some(factor) = factor {
+            if factor < MIN_EASE {
+                return Err(SchedError::InvalidEase(factor));
+            }
+        }
+
+        let today = self.today();
+        let mut changed = Vec::new();
+
+        for id in cards.iter() {
+            let target = match factor {
+                Some(factor) => factor,
+                None => self.initial_factor(*id),
+            };
+            let max_interval = self.max_interval(*id);
+            let card = match self.card_mut(*id) {
+                Some(card) => card,
+                None => continue,
+            };
+
+            let old = card.factor();
+            let is_review = matches!(card.card_type(), CardType::Review | CardType::Relearning);
+            if !is_review || old <= 0 || old == target {
+                continue;
+            }
+
+            let last_interval = card.interval();
+            card.set_factor(target);
+            if scale_intervals {
+                if let (Interval::Days(days), Due::Day(due)) = (last_interval, card.due()) {
+                    let scaled = (f64::from(days) * target as f64 / old as f64)
+                        .round()
+                        .min(max_interval)
+                        .max(1.0);
+                    let reviewed = due - days as i32;
+                    card.set_interval(Interval::Days(scaled as u32));
+                    if card.queue() == CardQueue::Review {
+                        card.set_due(Due::Day((reviewed + scaled as i32).max(today)));
+                    }
+                }
+            }
+            card.touch();
+            changed.push((*id, last_interval));
+        }
+
+        let count = changed.len();
+        self.log_manual(changed);
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Fixture;
+
+    fn spec(min: u32, max: u32, reset_interval: bool) -> DueDateSpec {
+        DueDateSpec {
+            min,
+            max,
+            reset_interval,
+        }
+    }
+
+    #[test]
+    fn due_date_specs() {
+        assert_eq!("0".parse(), Ok(spec(0, 0, false)));
+        assert_eq!("3-7".parse(), Ok(spec(3, 7, false)));
+        assert_eq!(" 3 - 7 ".parse(), Ok(spec(3, 7, false)));
+        assert_eq!("10!".parse(), Ok(spec(10, 10, true)));
+        assert_eq!("1-2!".parse(), Ok(spec(1, 2, true)));
+        assert_eq!("36500".parse(), Ok(spec(36500, 36500, false)));
+    }
+
+    #[test]
+    fn invalid_due_date_specs() {
+        for text in [
+            "",
+            "!",
+            "7-3",
+            "-3",
+            "3-",
+            "x",
+            "1.5",
+            "36501",
+            "0-36501",
+            "4000000000",
+        ] {
+            assert_eq!(
+                text.parse::<DueDateSpec>(),
+                Err(SchedError::InvalidDueDate(String::from(text))),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn forget_rejects_positions_out_of_range() {
+        let fixture = Fixture::new();
+        fixture
+            .note(1, "", &["a", "b"])
+            .review_card(11, 1, 1, 110, 20);
+
+        for next_pos in [-1, i64::from(u32::MAX), i64::MAX] {
+            let mut collection = fixture.collection();
+            collection.config_mut().set_next_pos(next_pos);
+            assert_eq!(
+                collection.forget(&[CardId(11)], false),
+                Err(SchedError::PositionOutOfRange)
+            );
+            let card = collection.card(CardId(11)).unwrap();
+            assert_eq!(card.card_type(), CardType::Review);
+        }
+
+        let mut collection = fixture.collection();
+        collection.config_mut().set_next_pos(7);
+        assert_eq!(collection.forget(&[CardId(11)], false), Ok(1));
+        assert_eq!(collection.card(CardId(11)).unwrap().due(), Due::Position(7));
+        assert_eq!(collection.config().next_pos(), 8);
+    }
+
+    #[test]
+    fn reset_ease_rejects_eases_below_the_minimum() {
+        let fixture = Fixture::new();
+        fixture
+            .note(1, "", &["a", "b"])
+            .review_card(11, 1, 1, 110, 20);
+        let mut collection = fixture.collection();
+
+        for factor in [-2500, 0, 1299] {
+            assert_eq!(
+                collection.reset_ease(&[CardId(11)], Some(factor), true),
+                Err(SchedError::InvalidEase(factor))
+            );
+        }
+        assert_eq!(collection.card(CardId(11)).unwrap().factor(), 2500);
+        assert_eq!(
+            collection.reset_ease(&[CardId(11)], Some(1300), false),
+            Ok(1)
+        );
+    }
+
+    #[test]
+    fn reset_ease_keeps_scaled_intervals_under_the_maximum() {
+        let fixture = Fixture::new();
+        fixture
+            .sql(r#"UPDATE col SET dconf = replace(dconf, '"maxIvl": 36500', '"maxIvl": 30')"#)
+            .note(1, "", &["a", "b"])
+            .review_card(11, 1, 1, 110, 20);
+        let mut collection = fixture.collection();
+
+        assert_eq!(
+            collection.reset_ease(&[CardId(11)], Some(5000), true),
+            Ok(1)
+        );
+        let card = collection.card(CardId(11)).unwrap();
+        assert_eq!(card.interval(), Interval::Days(30));
+        assert_eq!(card.due(), Due::Day(120));
+    }
+}
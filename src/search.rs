@@ -0,0 +1,916 @@
+/* This file is part of acp.
+ * Copyright (c) 2021 Wyatt Campbell
+ *
+ * See repository LICENSE for information.
+ */
+
+use crate::deck::{
+    Card, CardId, CardQueue, CardType, Collection, DeckId, Due, Interval, Model, Note, NoteId,
+    ReviewAnswer,
+};
+use regex::{Regex, RegexBuilder};
+use std::collections::HashSet;
+use std::fmt;
+
+// Search syntax follows https://docs.ankiweb.net/searching.html
+
+// Errors from parsing a search query
+#[derive(Debug, Clone, PartialEq)]
+pub enum SearchError {
+    UnbalancedParens,
+    UnterminatedQuote,
+    EmptyGroup,
+    DanglingOperator(String),
+    InvalidTerm(String),
+    InvalidRegex(String),
+}
+
+impl fmt::Display for SearchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SearchError::UnbalancedParens => write!(f, "unbalanced parentheses in search"),
+            SearchError::UnterminatedQuote => write!(f, "unterminated quote in search"),
+            SearchError::EmptyGroup => write!(f, "empty group in search"),
+            SearchError::DanglingOperator(op) => write!(f, "'{}' is missing an operand", op),
+            SearchError::InvalidTerm(term) => write!(f, "invalid search term '{}'", term),
+            SearchError::InvalidRegex(err) => write!(f, "invalid regex in search: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for SearchError {}
+
+// Comparison operator used by prop: searches
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+impl Comparison {
+    fn compare(self, lhs: f64, rhs: f64) -> bool {
+        match self {
+            Comparison::Equal => (lhs - rhs).abs() < f64::EPSILON,
+            Comparison::NotEqual => (lhs - rhs).abs() >= f64::EPSILON,
+            Comparison::Less => lhs < rhs,
+            Comparison::LessEqual => lhs <= rhs,
+            Comparison::Greater => lhs > rhs,
+            Comparison::GreaterEqual => lhs >= rhs,
+        }
+    }
+}
+
+// Card property compared by prop: searches
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Property {
+    Interval,
+    Due,
+    Position,
+    Reps,
+    Lapses,
+    Ease,
+}
+
+// Card states matched by is: searches
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    New,
+    Due,
+    Review,
+    Learn,
+    Suspended,
+    Buried,
+}
+
+// A single search term
+#[derive(Debug, Clone)]
+pub enum Term {
+    Text(String),
+    Regex(String),
+    Field(String, String),
+    Deck(String),
+    Tag(String),
+    Notetype(String),
+    Template(String),
+    State(State),
+    Flag(i64),
+    Prop(Property, Comparison, f64),
+    Rated(i64, Option<i64>), // Days, and the raw answer button
+    Added(i64),
+    NoteIds(Vec<NoteId>),
+    CardIds(Vec<CardId>),
+}
+
+// A parsed search query
+#[derive(Debug, Clone)]
+pub enum Node {
+    All,
+    Term(Term),
+    Not(Box<Node>),
+    And(Vec<Node>),
+    Or(Vec<Node>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Not,
+    And,
+    Or,
+    Text(String),
+}
+
+// Split a query into tokens, keeping quoted text together
+fn tokenize(query: &str) -> Result<Vec<Token>, SearchError> {
+    let mut tokens = Vec::new();
+    let mut chars = query.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' {
+            chars.next();
+            tokens.push(Token::Open);
+        } else if c == ')' {
+            chars.next();
+            tokens.push(Token::Close);
+        } else if c == '-' {
+            chars.next();
+            match chars.peek() {
+                Some(n) if !n.is_whitespace() => tokens.push(Token::Not),
+                _ => tokens.push(Token::Text(String::from("-"))),
+            }
+        } else {
+            let mut text = String::new();
+            let mut quoted = false;
+            let mut was_quoted = false;
+            while let Some(&c) = chars.peek() {
+                if !quoted && (c.is_whitespace() || c == '(' || c == ')') {
+                    break;
+                }
+                chars.next();
+                match c {
+                    '"' => {
+                        quoted = !quoted;
+                        was_quoted = true;
+                    }
+                    '\\' => match chars.next() {
+                        // These only need escaping for the tokenizer
+                        Some(e) if e == '"' || e == '(' || e == ')' || e.is_whitespace() => {
+                            text.push(e)
+                        }
+                        // Wildcard escapes are kept for the term parser
+                        Some(e) => {
+                            text.push('\\');
+                            text.push(e);
+                        }
+                        None => text.push('\\'),
+                    },
+                    _ => text.push(c),
+                }
+            }
+
+            if quoted {
+                return Err(SearchError::UnterminatedQuote);
+            }
+
+            if !was_quoted && text.eq_ignore_ascii_case("or") {
+                tokens.push(Token::Or);
+            } else if !was_quoted && text.eq_ignore_ascii_case("and") {
+                tokens.push(Token::And);
+            } else {
+                tokens.push(Token::Text(text));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    // expr := and ("or" and)*
+    fn parse_or(&mut self) -> Result<Node, SearchError> {
+        let mut nodes = vec![self.parse_and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            match self.peek() {
+                None | Some(Token::Close) | Some(Token::Or) | Some(Token::And) => {
+                    return Err(SearchError::DanglingOperator(String::from("or")))
+                }
+                _ => nodes.push(self.parse_and()?),
+            }
+        }
+
+        if nodes.len() == 1 {
+            Ok(nodes.pop().unwrap())
+        } else {
+            Ok(Node::Or(nodes))
+        }
+    }
+
+    // and := unary ("and"? unary)*
+    fn parse_and(&mut self) -> Result<Node, SearchError> {
+        let mut nodes = vec![self.parse_unary()?];
+        loop {
+            match self.peek() {
+                None | Some(Token::Close) | Some(Token::Or) => break,
+                Some(Token::And) => {
+                    self.next();
+                    match self.peek() {
+                        None | Some(Token::Close) | Some(Token::Or) | Some(Token::And) => {
+                            return Err(SearchError::DanglingOperator(String::from("and")))
+                        }
+                        _ => nodes.push(self.parse_unary()?),
+                    }
+                }
+                _ => nodes.push(self.parse_unary()?),
+            }
+        }
+
+        if nodes.len() == 1 {
+            Ok(nodes.pop().unwrap())
+        } else {
+            Ok(Node::And(nodes))
+        }
+    }
+
+    // unary := "-" unary | "(" expr ")" | term
+    fn parse_unary(&mut self) -> Result<Node, SearchError> {
+        match self.next() {
+            Some(Token::Not) => Ok(Node::Not(Box::new(self.parse_unary()?))),
+            Some(Token::Open) => {
+                match self.peek() {
+                    Some(Token::Close) => return Err(SearchError::EmptyGroup),
+                    None => return Err(SearchError::UnbalancedParens),
+                    _ => (),
+                }
+                let node = self.parse_or()?;
+                if self.next() != Some(Token::Close) {
+                    return Err(SearchError::UnbalancedParens);
+                }
+                Ok(node)
+            }
+            Some(Token::Text(text)) => Ok(Node::Term(parse_term(&text)?)),
+            Some(Token::Close) => Err(SearchError::UnbalancedParens),
+            Some(Token::And) => Err(SearchError::DanglingOperator(String::from("and"))),
+            Some(Token::Or) => Err(SearchError::DanglingOperator(String::from("or"))),
+            None => Err(SearchError::DanglingOperator(String::from("-"))),
+        }
+    }
+}
+
+// Parse a query string into a search tree
+pub fn parse(query: &str) -> Result<Node, SearchError> {
+    let tokens = tokenize(query)?;
+    if tokens.is_empty() {
+        return Ok(Node::All);
+    }
+
+    let mut parser = Parser { tokens, pos: 0 };
+    let node = parser.parse_or()?;
+    if parser.pos < parser.tokens.len() {
+        return Err(SearchError::UnbalancedParens);
+    }
+
+    Ok(node)
+}
+
+// Split "key:value" on the first unescaped colon
+fn split_key(text: &str) -> Option<(&str, &str)> {
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '\\' if !escaped => escaped = true,
+            ':' if !escaped => return Some((&text[..i], &text[i + 1..])),
+            _ => escaped = false,
+        }
+    }
+    None
+}
+
+// Remove escapes from text that is matched literally
+fn unescape(text: &str) -> String {
+    let mut out = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(e) = chars.next() {
+                out.push(e);
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn parse_ids<T: From<i64>>(text: &str, term: &str) -> Result<Vec<T>, SearchError> {
+    text.split(',')
+        .map(|id| {
+            id.trim()
+                .parse::<i64>()
+                .map(T::from)
+                .map_err(|_| SearchError::InvalidTerm(String::from(term)))
+        })
+        .collect()
+}
+
+fn parse_prop(text: &str, term: &str) -> Result<Term, SearchError> {
+    let invalid = || SearchError::InvalidTerm(String::from(term));
+
+    let op_start = text.find(['<', '>', '=', '!']).ok_or_else(invalid)?;
+    let (name, rest) = text.split_at(op_start);
+    let (cmp, value) = if let Some(v) = rest.strip_prefix("<=") {
+        (Comparison::LessEqual, v)
+    } else if let Some(v) = rest.strip_prefix(">=") {
+        (Comparison::GreaterEqual, v)
+    } else if let Some(v) = rest.strip_prefix("!=") {
+        (Comparison::NotEqual, v)
+    } else if let Some(v) = rest.strip_prefix('<') {
+        (Comparison::Less, v)
+    } else if let Some(v) = rest.strip_prefix('>') {
+        (Comparison::Greater, v)
+    } else if let Some(v) = rest.strip_prefix('=') {
+        (Comparison::Equal, v)
+    } else {
+        return Err(invalid());
+    };
+
+    let property = match name.to_ascii_lowercase().as_str() {
+        "ivl" => Property::Interval,
+        "due" => Property::Due,
+        "pos" => Property::Position,
+        "reps" => Property::Reps,
+        "lapses" => Property::Lapses,
+        "ease" => Property::Ease,
+        _ => return Err(invalid()),
+    };
+    let value = value.parse::<f64>().map_err(|_| invalid())?;
+
+    Ok(Term::Prop(property, cmp, value))
+}
+
+fn parse_term(text: &str) -> Result<Term, SearchError> {
+    let invalid = || SearchError::InvalidTerm(String::from(text));
+
+    let (key, value) = match split_key(text) {
+        Some((key, value)) if !key.is_empty() => (key, value),
+        _ => return Ok(Term::Text(String::from(text))),
+    };
+
+    let term = match key.to_ascii_lowercase().as_str() {
+        "deck" => Term::Deck(String::from(value)),
+        "tag" => Term::Tag(String::from(value)),
+        "note" => Term::Notetype(String::from(value)),
+        "card" => Term::Template(String::from(value)),
+        "re" => Term::Regex(unescape(value)),
+        "is" => Term::State(match value.to_ascii_lowercase().as_str() {
+            "new" => State::New,
+            "due" => State::Due,
+            "review" => State::Review,
+            "learn" => State::Learn,
+            "suspended" => State::Suspended,
+            "buried" => State::Buried,
+            _ => return Err(invalid()),
+        }),
+        "flag" => Term::Flag(value.parse::<i64>().map_err(|_| invalid())?),
+        "prop" => parse_prop(value, text)?,
+        "rated" => {
+            let (days, ease) = match value.split_once(':') {
+                Some((days, ease)) => {
+                    let ease = ease.parse::<i64>().map_err(|_| invalid())?;
+                    if !(1..=4).contains(&ease) {
+                        return Err(invalid());
+                    }
+                    (days, Some(ease))
+                }
+                None => (value, None),
+            };
+            Term::Rated(days.parse::<i64>().map_err(|_| invalid())?.max(1), ease)
+        }
+        "added" => Term::Added(value.parse::<i64>().map_err(|_| invalid())?.max(1)),
+        "nid" => Term::NoteIds(parse_ids(value, text)?),
+        "cid" => Term::CardIds(parse_ids(value, text)?),
+        _ => Term::Field(unescape(key), String::from(value)),
+    };
+
+    Ok(term)
+}
+
+// Convert an Anki wildcard pattern (* and _) into a case insensitive regex
+fn wildcard_regex(pattern: &str, anchored: bool) -> Result<Regex, SearchError> {
+    let mut re = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(e) = chars.next() {
+                    re.push_str(&regex::escape(&e.to_string()));
+                }
+            }
+            '*' => re.push_str(".*"),
+            '_' => re.push('.'),
+            _ => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    if anchored {
+        re = format!("^{}$", re);
+    }
+
+    RegexBuilder::new(&re)
+        .case_insensitive(true)
+        .dot_matches_new_line(true)
+        .build()
+        .map_err(|err| SearchError::InvalidRegex(err.to_string()))
+}
+
+// A term with its patterns compiled against a collection
+enum Matcher {
+    All,
+    Text(Regex),
+    Regex(Regex),
+    Field(Regex, Regex),
+    Decks(HashSet<DeckId>),
+    Tag(Regex),
+    NoTags,
+    Notetype(Regex),
+    TemplateOrdinal(i64),
+    TemplateName(Regex),
+    State(State),
+    Flag(i64),
+    Prop(Property, Comparison, f64),
+    Cards(HashSet<CardId>),
+    AddedSince(i64),
+    Notes(HashSet<NoteId>),
+    Not(Box<Matcher>),
+    And(Vec<Matcher>),
+    Or(Vec<Matcher>),
+}
+
+// Everything needed to test one card
+struct Context<'a> {
+    card: &'a Card,
+    note: Option<&'a Note>,
+    model: Option<&'a Model>,
+    today: i32,
+    now: i64,
+}
+
+// Start of the current collection day in seconds since epoch
+fn day_start(collection: &Collection) -> i64 {
+    collection.crt() + i64::from(collection.today()) * 86400
+}
+
+fn compile(node: &Node, collection: &Collection) -> Result<Matcher, SearchError> {
+    let matcher = match node {
+        Node::All => Matcher::All,
+        Node::Not(inner) => Matcher::Not(Box::new(compile(inner, collection)?)),
+        Node::And(nodes) => Matcher::And(
+            nodes
+                .iter()
+                .map(|n| compile(n, collection))
+                .collect::<Result<_, _>>()?,
+        ),
+        Node::Or(nodes) => Matcher::Or(
+            nodes
+                .iter()
+                .map(|n| compile(n, collection))
+                .collect::<Result<_, _>>()?,
+        ),
+        Node::Term(term) => compile_term(term, collection)?,
+    };
+
+    Ok(matcher)
+}
+
+fn compile_term(term: &Term, collection: &Collection) -> Result<Matcher, SearchError> {
+    let matcher = match term {
+        Term::Text(text) => Matcher::Text(wildcard_regex(text, false)?),
+        Term::Regex(re) => Matcher::Regex(
+            RegexBuilder::new(re)
+                .case_insensitive(true)
+                .build()
+                .map_err(|err| SearchError::InvalidRegex(err.to_string()))?,
+        ),
+        Term::Field(name, value) => {
+            Matcher::Field(wildcard_regex(name, true)?, wildcard_regex(value, true)?)
+        }
+        Term::Deck(name) => {
+            let mut decks = HashSet::new();
+            if name == "filtered" {
+                decks.extend(
+                    collection
+                        .decks()
+                        .iter()
+                        .filter(|d| d.is_dynamic())
+                        .map(|d| d.id()),
+                );
+            } else {
+                // A deck matches if it or any of its parents match
+                let re = wildcard_regex(name, true)?;
+                for deck in collection.decks() {
+                    let deck_name = deck.name();
+                    let matched = deck_name
+                        .match_indices("::")
+                        .map(|(i, _)| &deck_name[..i])
+                        .chain(std::iter::once(deck_name))
+                        .any(|prefix| re.is_match(prefix));
+                    if matched {
+                        decks.insert(deck.id());
+                    }
+                }
+            }
+            Matcher::Decks(decks)
+        }
+        Term::Tag(tag) => {
+            if tag.eq_ignore_ascii_case("none") {
+                Matcher::NoTags
+            } else {
+                let re = wildcard_regex(tag, false)?;
+                let re = RegexBuilder::new(&format!("^(?:{})(?:::.*)?$", re.as_str()))
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|err| SearchError::InvalidRegex(err.to_string()))?;
+                Matcher::Tag(re)
+            }
+        }
+        Term::Notetype(name) => Matcher::Notetype(wildcard_regex(name, true)?),
+        Term::Template(template) => match template.parse::<i64>() {
+            Ok(ord) => Matcher::TemplateOrdinal(ord - 1),
+            Err(_) => Matcher::TemplateName(wildcard_regex(template, true)?),
+        },
+        Term::State(state) => Matcher::State(*state),
+        Term::Flag(flag) => Matcher::Flag(*flag),
+        Term::Prop(property, cmp, value) => Matcher::Prop(*property, *cmp, *value),
+        Term::Rated(days, ease) => {
+            let cutoff = (day_start(collection) - (days - 1) * 86400) * 1000;
+            let version = collection.scheduler_version();
+            Matcher::Cards(
+                collection
+                    .revlog()
+                    .iter()
+                    .filter(|r| r.id().0 >= cutoff)
+                    // Anki matches the button number, whatever it meant for that entry
+                    .filter(|r| match ease {
+                        Some(ease) => ReviewAnswer::into_i64(r.ease(), r.kind(), version) == *ease,
+                        // Entries written by rescheduling have no answer, Anki skips them
+                        None => r.ease().is_some(),
+                    })
+                    .map(|r| r.card_id())
+                    .collect(),
+            )
+        }
+        Term::Added(days) => {
+            Matcher::AddedSince((day_start(collection) - (days - 1) * 86400) * 1000)
+        }
+        Term::NoteIds(ids) => Matcher::Notes(ids.iter().copied().collect()),
+        Term::CardIds(ids) => Matcher::Cards(ids.iter().copied().collect()),
+    };
+
+    Ok(matcher)
+}
+
+fn matches_state(state: State, ctx: &Context) -> bool {
+    let card = ctx.card;
+    match state {
+        State::New => card.card_type() == CardType::New,
+        State::Review => matches!(card.card_type(), CardType::Review | CardType::Relearning),
+        State::Learn => matches!(
+            card.queue(),
+            CardQueue::Learning | CardQueue::InLearning | CardQueue::Preview
+        ),
+        State::Suspended => card.queue() == CardQueue::Suspended,
+        State::Buried => matches!(card.queue(), CardQueue::Buried | CardQueue::UserBuried),
+        State::Due => match (card.queue(), card.due()) {
+            (CardQueue::Review, Due::Day(day)) | (CardQueue::InLearning, Due::Day(day)) => {
+                day <= ctx.today
+            }
+            (CardQueue::Learning, Due::Timestamp(ts)) => ts <= ctx.now,
+            _ => false,
+        },
+    }
+}
+
+fn matches_prop(property: Property, cmp: Comparison, value: f64, ctx: &Context) -> bool {
+    let card = ctx.card;
+    let lhs = match property {
+        Property::Interval => match card.interval() {
+            Interval::Days(days) => f64::from(days),
+            Interval::Seconds(_) => 0.0,
+        },
+        Property::Due => match (card.queue(), card.due()) {
+            (CardQueue::Review, Due::Day(day)) | (CardQueue::InLearning, Due::Day(day)) => {
+                f64::from(day - ctx.today)
+            }
+            _ => return false,
+        },
+        Property::Position => match (card.card_type(), card.due()) {
+            (CardType::New, Due::Position(pos)) => f64::from(pos),
+            _ => return false,
+        },
+        Property::Reps => card.reps() as f64,
+        Property::Lapses => card.lapses() as f64,
+        Property::Ease => {
+            if card.card_type() == CardType::New {
+                return false;
+            }
+            card.factor() as f64 / 1000.0
+        }
+    };
+
+    cmp.compare(lhs, value)
+}
+
+impl Matcher {
+    fn matches(&self, ctx: &Context) -> bool {
+        let card = ctx.card;
+        match self {
+            Matcher::All => true,
+            Matcher::Not(inner) => !inner.matches(ctx),
+            Matcher::And(matchers) => matchers.iter().all(|m| m.matches(ctx)),
+            Matcher::Or(matchers) => matchers.iter().any(|m| m.matches(ctx)),
+            Matcher::Text(re) | Matcher::Regex(re) => ctx
+                .note
+                .map(|n| n.fields().iter().any(|f| re.is_match(f)))
+                .unwrap_or(false),
+            Matcher::Field(name, value) => match (ctx.note, ctx.model) {
+                (Some(note), Some(model)) => model
+                    .fields()
+                    .iter()
+                    .filter(|f| name.is_match(f.name()))
+                    .filter_map(|f| note.fields().get(f.ordinal() as usize))
+                    .any(|f| value.is_match(f)),
+                _ => false,
+            },
+            Matcher::Decks(decks) => {
+                decks.contains(&card.deck_id()) || decks.contains(&card.original_deck_id())
+            }
+            Matcher::Tag(re) => ctx
+                .note
+                .map(|n| n.tags().iter().any(|t| re.is_match(t)))
+                .unwrap_or(false),
+            Matcher::NoTags => ctx.note.map(|n| n.tags().is_empty()).unwrap_or(false),
+            Matcher::Notetype(re) => ctx.model.map(|m| re.is_match(m.name())).unwrap_or(false),
+            Matcher::TemplateOrdinal(ord) => card.ordinal() == *ord,
+            Matcher::TemplateName(re) => ctx
+                .model
+                .map(|m| {
+                    m.templates()
+                        .iter()
+                        .any(|t| t.ordinal() == card.ordinal() && re.is_match(t.name()))
+                })
+                .unwrap_or(false),
+            Matcher::State(state) => matches_state(*state, ctx),
+            Matcher::Flag(flag) => card.flags() & 0b111 == *flag,
+            Matcher::Prop(property, cmp, value) => matches_prop(*property, *cmp, *value, ctx),
+            Matcher::Cards(ids) => ids.contains(&card.id()),
+            Matcher::AddedSince(cutoff) => card.id().0 >= *cutoff,
+            Matcher::Notes(ids) => ids.contains(&card.note_id()),
+        }
+    }
+}
+
+impl Collection {
+    // Find every card matching an Anki search query
+    pub fn search_cards(&self, query: &str) -> Result<Vec<CardId>, SearchError> {
+        let matcher = compile(&parse(query)?, self)?;
+        let today = self.today();
+        let now = crate::deck::now_millis() / 1000;
+
+        let cards = self
+            .cards()
+            .iter()
+            .filter(|card| {
+                let note = self.note(card.note_id());
+                let ctx = Context {
+                    card,
+                    note: note.map(|n| n.note()),
+                    model: note.and_then(|n| n.model()),
+                    today,
+                    now,
+                };
+                matcher.matches(&ctx)
+            })
+            .map(|card| card.id())
+            .collect();
+
+        Ok(cards)
+    }
+
+    // Find every note with at least one card matching an Anki search query
+    pub fn search_notes(&self, query: &str) -> Result<Vec<NoteId>, SearchError> {
+        let cards: HashSet<CardId> = self.search_cards(query)?.into_iter().collect();
+
+        let notes = self
+            .notes()
+            .iter()
+            .filter(|note| {
+                self.note(note.id())
+                    .map(|n| n.cards().any(|c| cards.contains(&c.id())))
+                    .unwrap_or(false)
+            })
+            .map(|note| note.id())
+            .collect();
+
+        Ok(notes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Fixture;
+
+    // Compact form of a tree so tests can compare whole queries
+    fn show(node: &Node) -> String {
+        let list = |nodes: &[Node], sep: &str| {
+            let parts: Vec<String> = nodes.iter().map(show).collect();
+            format!("({})", parts.join(sep))
+        };
+        match node {
+            Node::All => String::from("*"),
+            Node::Not(inner) => format!("-{}", show(inner)),
+            Node::And(nodes) => list(nodes, " & "),
+            Node::Or(nodes) => list(nodes, " | "),
+            Node::Term(Term::Text(text)) => format!("{:?}", text),
+            Node::Term(term) => format!("{:?}", term),
+        }
+    }
+
+    fn parsed(query: &str) -> String {
+        show(&parse(query).unwrap())
+    }
+
+    #[test]
+    fn empty_query_matches_everything() {
+        assert_eq!(parsed(""), "*");
+        assert_eq!(parsed("   "), "*");
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(parsed("a b or c"), r#"(("a" & "b") | "c")"#);
+        assert_eq!(parsed("a or b c"), r#"("a" | ("b" & "c"))"#);
+        assert_eq!(parsed("a and b OR c"), r#"(("a" & "b") | "c")"#);
+        assert_eq!(parsed("a (b or c)"), r#"("a" & ("b" | "c"))"#);
+    }
+
+    #[test]
+    fn negation() {
+        assert_eq!(parsed("-a b"), r#"(-"a" & "b")"#);
+        assert_eq!(parsed("-(a or b)"), r#"-("a" | "b")"#);
+        assert_eq!(parsed("--a"), r#"--"a""#);
+        // A lone dash is text, not an operator
+        assert_eq!(parsed("a - b"), r#"("a" & "-" & "b")"#);
+        assert_eq!(parsed("a -"), r#"("a" & "-")"#);
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(parsed(r#""a b" c"#), r#"("a b" & "c")"#);
+        assert_eq!(parsed(r#"front:"x y""#), r#"Field("front", "x y")"#);
+        assert_eq!(parsed(r#""or""#), r#""or""#);
+        assert_eq!(parsed(r#""(a)""#), r#""(a)""#);
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(parsed(r"a\ b"), r#""a b""#);
+        assert_eq!(parsed(r#"\"a\""#), r#""\"a\"""#);
+        assert_eq!(parsed(r"\(a\)"), r#""(a)""#);
+        // Wildcard escapes are left for the matcher
+        assert_eq!(parsed(r"a\*"), r#""a\\*""#);
+        assert_eq!(parsed(r"a\:b:c"), r#"Field("a:b", "c")"#);
+    }
+
+    #[test]
+    fn terms() {
+        assert_eq!(parsed("deck:a::b"), r#"Deck("a::b")"#);
+        assert_eq!(parsed("IS:Due"), "State(Due)");
+        assert_eq!(parsed("rated:3:1"), "Rated(3, Some(1))");
+        assert_eq!(parsed("rated:0"), "Rated(1, None)");
+        assert_eq!(parsed("prop:ivl>=10"), "Prop(Interval, GreaterEqual, 10.0)");
+        assert_eq!(parsed("nid:1,2"), "NoteIds([NoteId(1), NoteId(2)])");
+        assert_eq!(parsed(":a"), r#"":a""#);
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert_eq!(parse("(a").unwrap_err(), SearchError::UnbalancedParens);
+        assert_eq!(parse("a)").unwrap_err(), SearchError::UnbalancedParens);
+        assert_eq!(parse("()").unwrap_err(), SearchError::EmptyGroup);
+        assert_eq!(parse(r#""a"#).unwrap_err(), SearchError::UnterminatedQuote);
+        assert_eq!(
+            parse("a or").unwrap_err(),
+            SearchError::DanglingOperator(String::from("or"))
+        );
+        assert_eq!(
+            parse("and a").unwrap_err(),
+            SearchError::DanglingOperator(String::from("and"))
+        );
+    }
+
+    // Default has cards 11 and 12 (in Default::Sub), Other has card 13, suspended
+    // Card 11 was answered good yesterday, card 12 only has a rescheduling entry
+    fn collection() -> Collection {
+        let fixture = Fixture::new();
+        fixture
+            .note(1, "Lang::English greeting", &["hello", "world"])
+            .note(2, "lang::spanish animal", &["dog", "perro"])
+            .note(3, "language", &["cat", "gato"])
+            .review_card(11, 1, 1, 100, 10)
+            .new_card(12, 2, 2, 1)
+            .review_card(13, 3, 3, 90, 5)
+            .sql("UPDATE cards SET queue = -1 WHERE id = 13")
+            .revlog(86400, 11, 3, 1)
+            .revlog(60, 12, 0, 4);
+        fixture.collection()
+    }
+
+    fn search(collection: &Collection, query: &str) -> Vec<i64> {
+        let mut ids: Vec<i64> = collection
+            .search_cards(query)
+            .unwrap()
+            .into_iter()
+            .map(|id| id.0)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn deck_includes_children() {
+        let collection = collection();
+        assert_eq!(search(&collection, "deck:Default"), [11, 12]);
+        assert_eq!(search(&collection, "deck:default::sub"), [12]);
+        assert_eq!(search(&collection, "-deck:Default"), [13]);
+    }
+
+    #[test]
+    fn tag_includes_children_and_ignores_case() {
+        let collection = collection();
+        assert_eq!(search(&collection, "tag:lang"), [11, 12]);
+        assert_eq!(search(&collection, "tag:LANG::english"), [11]);
+        assert_eq!(search(&collection, "tag:lang*"), [11, 12, 13]);
+    }
+
+    #[test]
+    fn card_states() {
+        let collection = collection();
+        assert_eq!(search(&collection, "is:new"), [12]);
+        assert_eq!(search(&collection, "is:review"), [11, 13]);
+        assert_eq!(search(&collection, "is:suspended"), [13]);
+        assert_eq!(search(&collection, "is:review -is:suspended"), [11]);
+    }
+
+    #[test]
+    fn rated_skips_entries_without_an_answer() {
+        let collection = collection();
+        assert_eq!(search(&collection, "rated:2"), [11]);
+        assert_eq!(search(&collection, "rated:2:3"), [11]);
+        assert!(search(&collection, "rated:2:1").is_empty());
+    }
+
+    #[test]
+    fn invalid_terms() {
+        for query in [
+            "is:odd",
+            "flag:x",
+            "rated:1:0",
+            "rated:1:5",
+            "rated:x",
+            "prop:ivl",
+            "prop:size>1",
+            "prop:ivl>x",
+            "nid:1,x",
+        ] {
+            assert_eq!(
+                parse(query).unwrap_err(),
+                SearchError::InvalidTerm(String::from(query)),
+                "{}",
+                query
+            );
+        }
+    }
+}
@@ -0,0 +1,465 @@
+/* This file is part of acp.
+ * Copyright (c) 2021 Wyatt Campbell
+ *
+ * See repository LICENSE for information.
+ */
+
+use crate::deck::{
+    CardQueue, CardType, Collection, DeckConfig, DeckId, Due, ReviewAnswer, ReviewLog, RevlogKind,
+};
+use crate::stats::{button_index, interval_days, MATURE_INTERVAL};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Monte-Carlo simulation of future study, answering cards with buttons sampled from
+// how the collection's reviews have gone so far
+
+// Button frequencies used when the review log has little history, again, hard, good, easy
+const LEARNING_PRIOR: [f64; 4] = [0.2, 0.05, 0.65, 0.1];
+const YOUNG_PRIOR: [f64; 4] = [0.12, 0.1, 0.68, 0.1];
+const MATURE_PRIOR: [f64; 4] = [0.08, 0.1, 0.72, 0.1];
+// Weight of the priors, in reviews
+const PRIOR_WEIGHT: f64 = 20.0;
+// Answers to a learning card in one day before it is left for tomorrow
+const MAX_LEARNING_ANSWERS: u32 = 20;
+
+// Probabilities of pressing each button, and seconds taken per answer
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RecallModel {
+    pub learning: [f64; 4],
+    pub young: [f64; 4],
+    pub mature: [f64; 4],
+    pub learning_time: f64,
+    pub review_time: f64,
+}
+
+impl Default for RecallModel {
+    fn default() -> Self {
+        RecallModel {
+            learning: LEARNING_PRIOR,
+            young: YOUNG_PRIOR,
+            mature: MATURE_PRIOR,
+            learning_time: 10.0,
+            review_time: 8.0,
+        }
+    }
+}
+
+fn button(index: usize) -> ReviewAnswer {
+    match index {
+        0 => ReviewAnswer::Wrong,
+        1 => ReviewAnswer::Hard,
+        2 => ReviewAnswer::OK,
+        _ => ReviewAnswer::Easy,
+    }
+}
+
+// Blend observed button counts with a prior into probabilities
+fn smooth(counts: [u32; 4], prior: [f64; 4]) -> [f64; 4] {
+    let total: f64 = counts.iter().map(|c| f64::from(*c)).sum::<f64>() + PRIOR_WEIGHT;
+    let mut probabilities = [0.0; 4];
+    for i in 0..4 {
+        probabilities[i] = (f64::from(counts[i]) + prior[i] * PRIOR_WEIGHT) / total;
+    }
+    probabilities
+}
+
+impl RecallModel {
+    // Fit button probabilities and answer times from the review log
+    pub fn fit(revlog: &[ReviewLog]) -> Self {
+        let mut learning = [0; 4];
+        let mut young = [0; 4];
+        let mut mature = [0; 4];
+        let mut learning_time = (0, 0);
+        let mut review_time = (0, 0);
+
+        for entry in revlog.iter() {
+            let answer = match entry.ease() {
+                Some(answer) => answer,
+                None => continue,
+            };
+            match entry.kind() {
+                RevlogKind::Learning | RevlogKind::Relearning => {
+                    learning[button_index(answer)] += 1;
+                    learning_time = (learning_time.0 + entry.time(), learning_time.1 + 1);
+                }
+                RevlogKind::Review => {
+                    if interval_days(entry.last_interval()) < MATURE_INTERVAL {
+                        young[button_index(answer)] += 1;
+                    } else {
+                        mature[button_index(answer)] += 1;
+                    }
+                    review_time = (review_time.0 + entry.time(), review_time.1 + 1);
+                }
+                _ => (),
+            }
+        }
+
+        let defaults = RecallModel::default();
+        let average = |(total, count): (i64, i64), default: f64| {
+            if count == 0 {
+                default
+            } else {
+                total as f64 / count as f64 / 1000.0
+            }
+        };
+        RecallModel {
+            learning: smooth(learning, LEARNING_PRIOR),
+            young: smooth(young, YOUNG_PRIOR),
+            mature: smooth(mature, MATURE_PRIOR),
+            learning_time: average(learning_time, defaults.learning_time),
+            review_time: average(review_time, defaults.review_time),
+        }
+    }
+
+    fn sample(probabilities: &[f64; 4], rng: &mut StdRng) -> ReviewAnswer {
+        let mut roll: f64 = rng.gen();
+        for (i, p) in probabilities.iter().enumerate() {
+            if roll < *p {
+                return button(i);
+            }
+            roll -= p;
+        }
+        ReviewAnswer::Easy
+    }
+}
+
+// What to simulate
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SimulationOptions {
+    pub days: u32,
+    pub runs: u32,
+    pub new_per_day: Option<u32>, // overrides the deck presets when set
+    pub reviews_per_day: Option<u32>,
+    pub seed: Option<u64>,
+}
+
+impl Default for SimulationOptions {
+    fn default() -> Self {
+        SimulationOptions {
+            days: 30,
+            runs: 10,
+            new_per_day: None,
+            reviews_per_day: None,
+            seed: None,
+        }
+    }
+}
+
+// Average outcome of a simulated day
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct SimulatedDay {
+    pub new: f64,
+    pub learning: f64, // learning and relearning answers
+    pub reviews: f64,
+    pub lapses: f64,
+    pub time: f64, // seconds
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Simulation {
+    pub days: Vec<SimulatedDay>,
+    pub model: RecallModel,
+}
+
+impl Simulation {
+    // Share of simulated reviews that were not forgotten
+    pub fn retention(&self) -> Option<f64> {
+        let reviews: f64 = self.days.iter().map(|d| d.reviews).sum();
+        let lapses: f64 = self.days.iter().map(|d| d.lapses).sum();
+        if reviews == 0.0 {
+            None
+        } else {
+            Some(1.0 - lapses / reviews)
+        }
+    }
+
+    // Average seconds of study per day
+    pub fn average_time(&self) -> f64 {
+        if self.days.is_empty() {
+            0.0
+        } else {
+            self.days.iter().map(|d| d.time).sum::<f64>() / self.days.len() as f64
+        }
+    }
+}
+
+// Scheduling settings of a deck preset, with Anki's defaults for anything missing
+#[derive(Debug, Clone)]
+struct Settings {
+    new_per_day: u32,
+    reviews_per_day: u32,
+    learning_steps: usize,
+    relearning_steps: usize,
+    graduating: f64,
+    easy: f64,
+    initial_factor: f64,
+    ease4: f64,
+    interval_factor: f64,
+    max_interval: f64,
+    lapse_mult: f64,
+    min_interval: f64,
+}
+
+impl Settings {
+    fn new(config: Option<&DeckConfig>, options: &SimulationOptions) -> Self {
+        let new = config.and_then(|c| c.new_config());
+        let review = config.and_then(|c| c.review_config());
+        let lapse = config.and_then(|c| c.lapse_config());
+
+        let new_per_day = options
+            .new_per_day
+            .or_else(|| new.map(|n| n.per_day().max(0) as u32))
+            .unwrap_or(20);
+        let reviews_per_day = options
+            .reviews_per_day
+            .or_else(|| review.map(|r| r.per_day().max(0) as u32))
+            .unwrap_or(200);
+        let intervals = new.map(|n| n.intervals().to_vec()).unwrap_or_default();
+
+        Settings {
+            new_per_day,
+            reviews_per_day,
+            learning_steps: new.map(|n| n.delays().len()).unwrap_or(2),
+            relearning_steps: lapse.map(|l| l.delays().len()).unwrap_or(1),
+            graduating: intervals.first().copied().unwrap_or(1).max(1) as f64,
+            easy: intervals.get(1).copied().unwrap_or(4).max(1) as f64,
+            initial_factor: new.map(|n| n.initial_factor()).unwrap_or(2500) as f64 / 1000.0,
+            ease4: review
+                .map(|r| r.ease4())
+                .filter(|e| *e > 0.0)
+                .unwrap_or(1.3),
+            interval_factor: review
+                .map(|r| r.interval_factor())
+                .filter(|f| *f > 0.0)
+                .unwrap_or(1.0),
+            max_interval: review
+                .map(|r| r.max_interval())
+                .filter(|m| *m > 0.0)
+                .unwrap_or(36500.0),
+            lapse_mult: lapse.map(|l| l.mult()).unwrap_or(0.0),
+            min_interval: lapse.map(|l| l.min_interval()).unwrap_or(1).max(1) as f64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum SimState {
+    New,
+    Learning,
+    Review,
+}
+
+#[derive(Debug, Clone)]
+struct SimCard {
+    deck: DeckId,
+    state: SimState,
+    position: u32,
+    due: i32, // days from the start of the simulation
+    interval: f64,
+    factor: f64,
+}
+
+impl Collection {
+    // Simulate study with the review log fitted recall model
+    pub fn simulate(&self, options: &SimulationOptions) -> Simulation {
+        self.simulate_with_model(options, RecallModel::fit(self.revlog()))
+    }
+
+    // Simulate study with a given recall model, averaging over several runs
+    pub fn simulate_with_model(
+        &self,
+        options: &SimulationOptions,
+        model: RecallModel,
+    ) -> Simulation {
+        let mut settings = HashMap::new();
+        let mut cards = Vec::new();
+        for card in self.cards().iter() {
+            let deck = match card.original_deck_id().0 {
+                0 => card.deck_id(),
+                _ => card.original_deck_id(),
+            };
+            settings.entry(deck).or_insert_with(|| {
+                let config = self.deck(deck).and_then(|d| d.config());
+                Settings::new(config, options)
+            });
+
+            let (state, position, due) = match (card.queue(), card.due()) {
+                (CardQueue::Suspended, _) => continue,
+                (_, Due::Position(position)) => (SimState::New, position, 0),
+                (_, Due::Day(day)) if card.card_type() == CardType::Review => {
+                    (SimState::Review, 0, day - self.today())
+                }
+                _ => (SimState::Learning, 0, 0),
+            };
+            cards.push(SimCard {
+                deck,
+                state,
+                position,
+                due: due.max(0),
+                interval: f64::from(interval_days(card.interval())),
+                factor: card.factor() as f64 / 1000.0,
+            });
+        }
+        cards.sort_by_key(|c| (c.state != SimState::New, c.position));
+
+        let mut rng = match options.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let runs = options.runs.max(1);
+        let mut days = vec![SimulatedDay::default(); options.days as usize];
+        for _ in 0..runs {
+            let run = simulate_run(cards.clone(), &settings, &model, options.days, &mut rng);
+            for (total, day) in days.iter_mut().zip(run) {
+                total.new += day.new / f64::from(runs);
+                total.learning += day.learning / f64::from(runs);
+                total.reviews += day.reviews / f64::from(runs);
+                total.lapses += day.lapses / f64::from(runs);
+                total.time += day.time / f64::from(runs);
+            }
+        }
+
+        Simulation { days, model }
+    }
+}
+
+// Answer a card through its learning steps, returning the answers given
+fn learn(steps: usize, model: &RecallModel, rng: &mut StdRng) -> (u32, ReviewAnswer) {
+    let mut step = 0;
+    let mut answers = 0;
+    loop {
+        answers += 1;
+        let answer = RecallModel::sample(&model.learning, rng);
+        match answer {
+            ReviewAnswer::Wrong => step = 0,
+            ReviewAnswer::Hard => (),
+            ReviewAnswer::OK => step += 1,
+            ReviewAnswer::Easy => return (answers, answer),
+        }
+        if step >= steps || answers >= MAX_LEARNING_ANSWERS {
+            return (answers, ReviewAnswer::OK);
+        }
+    }
+}
+
+fn simulate_run(
+    mut cards: Vec<SimCard>,
+    settings: &HashMap<DeckId, Settings>,
+    model: &RecallModel,
+    days: u32,
+    rng: &mut StdRng,
+) -> Vec<SimulatedDay> {
+    let mut result = Vec::with_capacity(days as usize);
+
+    for today in 0..days as i32 {
+        let mut day = SimulatedDay::default();
+        let mut new_done: HashMap<DeckId, u32> = HashMap::new();
+        let mut reviews_done: HashMap<DeckId, u32> = HashMap::new();
+
+        // Most overdue reviews first, as limits leave a backlog
+        let mut due: Vec<usize> = (0..cards.len())
+            .filter(|i| cards[*i].state != SimState::New && cards[*i].due <= today)
+            .collect();
+        due.sort_by_key(|i| cards[*i].due);
+
+        for i in due.into_iter() {
+            let card = &mut cards[i];
+            let config = &settings[&card.deck];
+
+            if card.state == SimState::Learning {
+                let (answers, answer) = learn(config.learning_steps, model, rng);
+                day.learning += f64::from(answers);
+                day.time += f64::from(answers) * model.learning_time;
+                graduate(card, config, answer, today);
+                continue;
+            }
+
+            let done = reviews_done.entry(card.deck).or_default();
+            if *done >= config.reviews_per_day {
+                continue;
+            }
+            *done += 1;
+
+            let probabilities = if card.interval < f64::from(MATURE_INTERVAL) {
+                &model.young
+            } else {
+                &model.mature
+            };
+            let answer = RecallModel::sample(probabilities, rng);
+            day.reviews += 1.0;
+            day.time += model.review_time;
+
+            let elapsed = card.interval + f64::from(today - card.due);
+            let interval = match answer {
+                ReviewAnswer::Wrong => {
+                    day.lapses += 1.0;
+                    let steps = config.relearning_steps as f64;
+                    day.learning += steps;
+                    day.time += steps * model.learning_time;
+                    card.factor = (card.factor - 0.2).max(1.3);
+                    (card.interval * config.lapse_mult).max(config.min_interval)
+                }
+                ReviewAnswer::Hard => {
+                    card.factor = (card.factor - 0.15).max(1.3);
+                    (card.interval * 1.2).max(card.interval + 1.0)
+                }
+                ReviewAnswer::OK => {
+                    let overdue = elapsed - card.interval;
+                    ((card.interval + overdue / 2.0) * card.factor).max(card.interval + 1.0)
+                }
+                ReviewAnswer::Easy => {
+                    card.factor += 0.15;
+                    (elapsed * card.factor * config.ease4).max(card.interval + 1.0)
+                }
+            };
+            let interval = if answer == ReviewAnswer::Wrong {
+                interval
+            } else {
+                interval * config.interval_factor
+            };
+            card.interval = interval.min(config.max_interval).round().max(1.0);
+            card.due = today + card.interval as i32;
+        }
+
+        // Introduce new cards in queue order up to each deck's limit
+        for card in cards.iter_mut().filter(|c| c.state == SimState::New) {
+            let config = &settings[&card.deck];
+            let done = new_done.entry(card.deck).or_default();
+            if *done >= config.new_per_day {
+                continue;
+            }
+            *done += 1;
+
+            let (answers, answer) = learn(config.learning_steps, model, rng);
+            day.new += 1.0;
+            day.learning += f64::from(answers);
+            day.time += f64::from(answers) * model.learning_time;
+            card.factor = config.initial_factor;
+            graduate(card, config, answer, today);
+        }
+
+        result.push(day);
+    }
+
+    result
+}
+
+fn graduate(card: &mut SimCard, config: &Settings, answer: ReviewAnswer, today: i32) {
+    let interval = if card.state == SimState::Learning && card.interval >= 1.0 {
+        // Relearning cards keep their interval
+        card.interval
+    } else if answer == ReviewAnswer::Easy {
+        config.easy
+    } else {
+        config.graduating
+    };
+    if card.factor <= 0.0 {
+        card.factor = config.initial_factor;
+    }
+    card.state = SimState::Review;
+    card.interval = interval;
+    card.due = today + interval as i32;
+}
@@ -0,0 +1,356 @@
+/* This file is part of acp.
+ * Copyright (c) 2021 Wyatt Campbell
+ *
+ * See repository LICENSE for information.
+ */
+
+use crate::deck::{
+    CardId, CardQueue, CardType, Collection, DeckId, Due, Interval, ReviewAnswer, ReviewLog,
+    RevlogKind,
+};
+use crate::search::SearchError;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+// Statistics computed from the review log and cards, following Anki's Stats screen,
+// and a forecast of upcoming reviews from card due dates
+// Days are collection days relative to today (0 is today, -1 yesterday), hours are local
+// time, counted from the collection's day start and its rollover hour
+
+// Review cards at or past this interval in days are mature
+pub const MATURE_INTERVAL: u32 = 21;
+
+// Reviews done on a day, split by kind, with time spent in milliseconds
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct DayReviews {
+    pub learning: u32,
+    pub young: u32,
+    pub mature: u32,
+    pub relearning: u32,
+    pub filtered: u32,
+    pub time: i64,
+}
+
+impl DayReviews {
+    pub fn total(&self) -> u32 {
+        self.learning + self.young + self.mature + self.relearning + self.filtered
+    }
+}
+
+// Passed and failed reviews of review cards
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Retention {
+    pub passed: u32,
+    pub failed: u32,
+}
+
+impl Retention {
+    // Fraction of reviews passed, if there were any
+    pub fn rate(&self) -> Option<f64> {
+        let total = self.passed + self.failed;
+        if total == 0 {
+            None
+        } else {
+            Some(f64::from(self.passed) / f64::from(total))
+        }
+    }
+
+    fn record(&mut self, passed: bool) {
+        if passed {
+            self.passed += 1;
+        } else {
+            self.failed += 1;
+        }
+    }
+}
+
+// How often each answer button was pressed, indexed again, hard, good, easy
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct ButtonCounts {
+    pub learning: [u32; 4],
+    pub young: [u32; 4],
+    pub mature: [u32; 4],
+}
+
+// Reviews done in an hour of the day
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub struct HourReviews {
+    pub reviews: u32,
+    pub correct: u32,
+}
+
+// How many cards are in each state
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct CardCounts {
+    pub new: u32,
+    pub learning: u32,
+    pub relearning: u32,
+    pub young: u32,
+    pub mature: u32,
+    pub suspended: u32,
+    pub buried: u32,
+}
+
+// Everything shown on the stats screen
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Stats {
+    pub reviews_per_day: BTreeMap<i32, DayReviews>,
+    pub time_spent: i64, // milliseconds
+    pub young_retention: Retention,
+    pub mature_retention: Retention,
+    pub buttons: ButtonCounts,
+    pub hours: [HourReviews; 24],
+    pub added_per_day: BTreeMap<i32, u32>,
+    pub intervals: BTreeMap<u32, u32>, // review interval in days to card count
+    pub eases: BTreeMap<u32, u32>,     // ease in percent to card count
+    pub card_counts: CardCounts,
+}
+
+// Cards coming due on a future day
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct DayForecast {
+    pub learning: u32,
+    pub young: u32,
+    pub mature: u32,
+}
+
+impl DayForecast {
+    pub fn total(&self) -> u32 {
+        self.learning + self.young + self.mature
+    }
+}
+
+// Due counts for the coming days, index 0 being today
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Forecast {
+    pub days: Vec<DayForecast>,
+    pub overdue: u32, // review cards that were due before today
+}
+
+impl Forecast {
+    // Everything that can be studied today, including the backlog
+    pub fn due_today(&self) -> u32 {
+        self.days.first().map(|d| d.total()).unwrap_or(0) + self.overdue
+    }
+}
+
+pub(crate) fn button_index(answer: ReviewAnswer) -> usize {
+    match answer {
+        ReviewAnswer::Wrong => 0,
+        ReviewAnswer::Hard => 1,
+        ReviewAnswer::OK => 2,
+        ReviewAnswer::Easy => 3,
+    }
+}
+
+pub(crate) fn interval_days(interval: Interval) -> u32 {
+    match interval {
+        Interval::Days(days) => days,
+        Interval::Seconds(_) => 0,
+    }
+}
+
+impl Collection {
+    // Collection day of a time in milliseconds, relative to today
+    fn relative_day(&self, millis: i64) -> i32 {
+        let day = (millis / 1000 - self.crt()).div_euclid(86400) as i32;
+        day - self.today()
+    }
+
+    // Stats for the cards matching a search, over the last days or the whole history
+    pub fn stats(&self, query: &str, days: Option<u32>) -> Result<Stats, SearchError> {
+        let cards = self.search_cards(query)?;
+        Ok(self.stats_for_cards(&cards, days))
+    }
+
+    // Cards in a deck and its children, including cards moved to filtered decks
+    fn deck_tree_cards(&self, deck: DeckId) -> Vec<CardId> {
+        let parent = match self.deck(deck) {
+            Some(deck) => deck.name().to_string(),
+            None => return Vec::new(),
+        };
+        let children = format!("{}::", parent);
+        let decks: HashSet<DeckId> = self
+            .decks()
+            .iter()
+            .filter(|d| d.name() == parent || d.name().starts_with(&children))
+            .map(|d| d.id())
+            .collect();
+
+        self.cards()
+            .iter()
+            .filter(|c| decks.contains(&c.deck_id()) || decks.contains(&c.original_deck_id()))
+            .map(|c| c.id())
+            .collect()
+    }
+
+    // Stats for the cards in a deck and its children, over the last days or the whole history
+    pub fn stats_for_deck(&self, deck: DeckId, days: Option<u32>) -> Stats {
+        self.stats_for_cards(&self.deck_tree_cards(deck), days)
+    }
+
+    // Stats for a set of cards, over the last days or the whole history
+    pub fn stats_for_cards(&self, cards: &[CardId], days: Option<u32>) -> Stats {
+        let wanted: HashSet<CardId> = cards.iter().copied().collect();
+        let first_day = days.map(|d| 1 - d as i32);
+        let in_period = |day: i32| first_day.map(|first| day >= first).unwrap_or(true);
+
+        let mut stats = Stats::default();
+
+        let entries = self
+            .revlog()
+            .iter()
+            .filter(|r| wanted.contains(&r.card_id()))
+            .filter(|r| in_period(self.relative_day(r.id().0)));
+        for entry in entries {
+            self.record_review(&mut stats, entry);
+        }
+
+        for id in cards.iter() {
+            let card = match self.card(*id) {
+                Some(card) => card,
+                None => continue,
+            };
+
+            let added = self.relative_day(card.id().0);
+            if in_period(added) {
+                *stats.added_per_day.entry(added).or_default() += 1;
+            }
+
+            let counts = &mut stats.card_counts;
+            match card.queue() {
+                CardQueue::Suspended => counts.suspended += 1,
+                CardQueue::Buried | CardQueue::UserBuried => counts.buried += 1,
+                _ => match card.card_type() {
+                    CardType::New => counts.new += 1,
+                    CardType::Learning => counts.learning += 1,
+                    CardType::Relearning => counts.relearning += 1,
+                    CardType::Review => {
+                        if interval_days(card.interval()) < MATURE_INTERVAL {
+                            counts.young += 1;
+                        } else {
+                            counts.mature += 1;
+                        }
+                    }
+                },
+            }
+
+            if matches!(card.card_type(), CardType::Review | CardType::Relearning) {
+                *stats
+                    .intervals
+                    .entry(interval_days(card.interval()))
+                    .or_default() += 1;
+                *stats.eases.entry((card.factor() / 10) as u32).or_default() += 1;
+            }
+        }
+
+        stats
+    }
+
+    fn record_review(&self, stats: &mut Stats, entry: &ReviewLog) {
+        // Manual and rescheduled entries are not reviews
+        let answer = match entry.ease() {
+            Some(answer) => answer,
+            None => return,
+        };
+        let passed = answer != ReviewAnswer::Wrong;
+        let mature = interval_days(entry.last_interval()) >= MATURE_INTERVAL;
+
+        let day = stats
+            .reviews_per_day
+            .entry(self.relative_day(entry.id().0))
+            .or_default();
+        day.time += entry.time();
+        stats.time_spent += entry.time();
+
+        match entry.kind() {
+            RevlogKind::Learning => {
+                day.learning += 1;
+                stats.buttons.learning[button_index(answer)] += 1;
+            }
+            RevlogKind::Relearning => {
+                day.relearning += 1;
+                stats.buttons.learning[button_index(answer)] += 1;
+            }
+            RevlogKind::Filtered => day.filtered += 1,
+            RevlogKind::Review => {
+                if mature {
+                    day.mature += 1;
+                    stats.buttons.mature[button_index(answer)] += 1;
+                    stats.mature_retention.record(passed);
+                } else {
+                    day.young += 1;
+                    stats.buttons.young[button_index(answer)] += 1;
+                    stats.young_retention.record(passed);
+                }
+            }
+            RevlogKind::Manual | RevlogKind::Rescheduled => (),
+        }
+
+        // Collection days start at the rollover hour, so the hour into the day gives local time
+        let into_day = (entry.id().0 / 1000 - self.crt()).rem_euclid(86400) / 3600;
+        let hour = (self.config().rollover() + into_day).rem_euclid(24) as usize;
+        stats.hours[hour].reviews += 1;
+        if passed {
+            stats.hours[hour].correct += 1;
+        }
+    }
+
+    // Forecast for the whole collection over the next days
+    pub fn forecast(&self, days: u32) -> Forecast {
+        let cards: Vec<CardId> = self.cards().iter().map(|c| c.id()).collect();
+        self.forecast_for_cards(&cards, days)
+    }
+
+    // Forecast for a deck and its children over the next days
+    pub fn forecast_for_deck(&self, deck: DeckId, days: u32) -> Forecast {
+        self.forecast_for_cards(&self.deck_tree_cards(deck), days)
+    }
+
+    // Forecast for a set of cards over the next days, today included
+    pub fn forecast_for_cards(&self, cards: &[CardId], days: u32) -> Forecast {
+        let mut forecast = Forecast {
+            days: vec![DayForecast::default(); days as usize],
+            overdue: 0,
+        };
+
+        for id in cards.iter() {
+            let card = match self.card(*id) {
+                Some(card) => card,
+                None => continue,
+            };
+
+            let buried = match card.queue() {
+                CardQueue::Suspended | CardQueue::New => continue,
+                CardQueue::Buried | CardQueue::UserBuried => true,
+                _ => false,
+            };
+
+            let (day, learning) = match card.due() {
+                Due::Position(_) => continue,
+                Due::Day(day) => (day - self.today(), card.card_type() != CardType::Review),
+                Due::Timestamp(secs) => (self.relative_day(secs * 1000).max(0), true),
+            };
+            // Buried cards come back tomorrow at the earliest
+            let day = if buried { day.max(1) } else { day };
+
+            if day < 0 && !learning {
+                forecast.overdue += 1;
+                continue;
+            }
+            let slot = match forecast.days.get_mut(day.max(0) as usize) {
+                Some(slot) => slot,
+                None => continue,
+            };
+            if learning {
+                slot.learning += 1;
+            } else if interval_days(card.interval()) < MATURE_INTERVAL {
+                slot.young += 1;
+            } else {
+                slot.mature += 1;
+            }
+        }
+
+        forecast
+    }
+}
@@ -0,0 +1,354 @@
+/* This file is part of acp.
+ * Copyright (c) 2021 Wyatt Campbell
+ *
+ * See repository LICENSE for information.
+ */
+
+use crate::deck::{Collection, NoteId};
+use json;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+// Tags are case insensitive and hierarchical, with levels separated by "::"
+
+// Errors from tag operations
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagError {
+    InvalidTag(String), // Empty, or more than one tag
+}
+
+impl fmt::Display for TagError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TagError::InvalidTag(tag) => write!(f, "'{}' is not a single tag", tag),
+        }
+    }
+}
+
+impl std::error::Error for TagError {}
+
+// Parse the tag registry stored in the col table, a JSON object of tag to usn
+pub(crate) fn parse_registry(data: &str) -> BTreeMap<String, i64> {
+    let mut registry = BTreeMap::new();
+
+    if let Ok(parsed) = json::parse(data) {
+        for (tag, usn) in parsed.entries() {
+            registry.insert(String::from(tag), usn.as_i64().unwrap_or(-1));
+        }
+    }
+
+    registry
+}
+
+pub(crate) fn registry_to_json(registry: &BTreeMap<String, i64>) -> json::JsonValue {
+    let mut json = json::JsonValue::new_object();
+
+    for (tag, usn) in registry.iter() {
+        json.insert(tag, *usn).unwrap();
+    }
+
+    json
+}
+
+// Split user input into individual tags, dropping empty levels
+pub fn split_tags(tags: &str) -> Vec<String> {
+    tags.split_whitespace()
+        .map(|tag| {
+            tag.split("::")
+                .filter(|level| !level.is_empty())
+                .collect::<Vec<_>>()
+                .join("::")
+        })
+        .filter(|tag| !tag.is_empty())
+        .collect()
+}
+
+// Does tag equal parent or sit below it in the hierarchy
+pub fn tag_matches(tag: &str, parent: &str) -> bool {
+    let tag = tag.to_lowercase();
+    let parent = parent.to_lowercase();
+    tag == parent || tag.starts_with(&format!("{}::", parent))
+}
+
+// Remove case insensitive duplicates, keeping the first spelling
+fn dedup(tags: &mut Vec<String>) -> bool {
+    let mut seen = HashSet::new();
+    let len = tags.len();
+    tags.retain(|tag| seen.insert(tag.to_lowercase()));
+    tags.len() != len
+}
+
+impl Collection {
+    // Every tag in the registry
+    pub fn all_tags(&self) -> Vec<String> {
+        self.tag_registry().keys().cloned().collect()
+    }
+
+    // Spelling to use for a tag, reusing the case of the longest known parent
+    fn canonical_tag(&self, tag: &str, used: &HashMap<String, String>) -> String {
+        let known = |prefix: &str| {
+            let lower = prefix.to_lowercase();
+            used.get(&lower).cloned().or_else(|| {
+                self.tag_registry()
+                    .keys()
+                    .find(|t| t.to_lowercase() == lower)
+                    .cloned()
+            })
+        };
+
+        let mut end = tag.len();
+        loop {
+            if let Some(spelling) = known(&tag[..end]) {
+                return format!("{}{}", spelling, &tag[end..]);
+            }
+            match tag[..end].rfind("::") {
+                Some(i) => end = i,
+                None => return String::from(tag),
+            }
+        }
+    }
+
+    // Add space separated tags to notes, returning how many notes changed
+    pub fn add_tags(&mut self, notes: &[NoteId], tags: &str) -> usize {
+        let used = self.used_tags();
+        let mut tags: Vec<String> = split_tags(tags)
+            .iter()
+            .map(|t| self.canonical_tag(t, &used))
+            .collect();
+        dedup(&mut tags);
+        for tag in tags.iter() {
+            self.tag_registry_mut().entry(tag.clone()).or_insert(-1);
+        }
+
+        let mut changed = 0;
+        for id in notes.iter() {
+            if let Some(note) = self.note_mut(*id) {
+                let present: HashSet<String> =
+                    note.tags().iter().map(|t| t.to_lowercase()).collect();
+                let missing: Vec<String> = tags
+                    .iter()
+                    .filter(|t| !present.contains(&t.to_lowercase()))
+                    .cloned()
+                    .collect();
+                if !missing.is_empty() {
+                    let mut note_tags = note.tags().to_vec();
+                    note_tags.extend(missing);
+                    note.set_tags(note_tags);
+                    changed += 1;
+                }
+            }
+        }
+
+        changed
+    }
+
+    // Remove tags and their children from notes, returning how many notes changed
+    pub fn remove_tags(&mut self, notes: &[NoteId], tags: &str) -> usize {
+        let tags = split_tags(tags);
+
+        let mut changed = 0;
+        for id in notes.iter() {
+            if let Some(note) = self.note_mut(*id) {
+                let note_tags: Vec<String> = note
+                    .tags()
+                    .iter()
+                    .filter(|t| !tags.iter().any(|r| tag_matches(t, r)))
+                    .cloned()
+                    .collect();
+                if note_tags.len() != note.tags().len() {
+                    note.set_tags(note_tags);
+                    changed += 1;
+                }
+            }
+        }
+
+        changed
+    }
+
+    // Rename a tag and everything below it, returning how many notes changed
+    pub fn rename_tag(&mut self, old: &str, new: &str) -> Result<usize, TagError> {
+        let single = |text: &str| match split_tags(text).as_slice() {
+            [tag] => Ok(tag.clone()),
+            _ => Err(TagError::InvalidTag(String::from(text))),
+        };
+        let old = single(old)?;
+        let new = single(new)?;
+        let levels = old.split("::").count();
+
+        let rename = |tag: &String| {
+            if tag_matches(tag, &old) {
+                // Split after the same number of levels rather than old's length, case
+                // folding can change how many bytes a level takes
+                let rest = match tag.match_indices("::").nth(levels - 1) {
+                    Some((i, _)) => &tag[i..],
+                    None => "",
+                };
+                format!("{}{}", new, rest)
+            } else {
+                tag.clone()
+            }
+        };
+
+        let mut changed = 0;
+        for note in self.notes_mut().iter_mut() {
+            if note.tags().iter().any(|t| tag_matches(t, &old)) {
+                let mut note_tags: Vec<String> = note.tags().iter().map(rename).collect();
+                dedup(&mut note_tags);
+                note.set_tags(note_tags);
+                changed += 1;
+            }
+        }
+
+        let registry = std::mem::take(self.tag_registry_mut());
+        for (tag, usn) in registry.into_iter() {
+            let renamed = rename(&tag);
+            let usn = if renamed == tag { usn } else { -1 };
+            self.tag_registry_mut().insert(renamed, usn);
+        }
+
+        Ok(changed)
+    }
+
+    // Merge tags on each note that only differ by case, returning how many notes changed
+    pub fn dedup_tags(&mut self) -> usize {
+        let mut changed = 0;
+        for note in self.notes_mut().iter_mut() {
+            let mut note_tags = note.tags().to_vec();
+            if dedup(&mut note_tags) {
+                note.set_tags(note_tags);
+                changed += 1;
+            }
+        }
+
+        changed
+    }
+
+    // Registered tags that no note uses, either directly or through a child tag
+    pub fn unused_tags(&self) -> Vec<String> {
+        let used = self.used_tags();
+        self.tag_registry()
+            .keys()
+            .filter(|t| !used.contains_key(&t.to_lowercase()))
+            .cloned()
+            .collect()
+    }
+
+    // Drop unused tags from the registry, returning them
+    pub fn clear_unused_tags(&mut self) -> Vec<String> {
+        let unused = self.unused_tags();
+        for tag in unused.iter() {
+            self.tag_registry_mut().remove(tag);
+        }
+        unused
+    }
+
+    // Lowercased tags used by notes, including their parents, mapped to their spelling
+    fn used_tags(&self) -> HashMap<String, String> {
+        let mut used = HashMap::new();
+        for note in self.notes() {
+            for tag in note.tags() {
+                let mut end = tag.len();
+                loop {
+                    let prefix = &tag[..end];
+                    used.entry(prefix.to_lowercase())
+                        .or_insert_with(|| String::from(prefix));
+                    match prefix.rfind("::") {
+                        Some(i) => end = i,
+                        None => break,
+                    }
+                }
+            }
+        }
+        used
+    }
+
+    // Regenerate the registry from the notes so Anki's browser shows the right tags
+    pub fn rebuild_tag_registry(&mut self) {
+        let used = self.used_tags();
+        let old = std::mem::take(self.tag_registry_mut());
+        let old: HashMap<String, (String, i64)> = old
+            .into_iter()
+            .map(|(tag, usn)| (tag.to_lowercase(), (tag, usn)))
+            .collect();
+
+        let registry = self.tag_registry_mut();
+        for (lower, spelling) in used.into_iter() {
+            match old.get(&lower) {
+                Some((tag, usn)) => registry.insert(tag.clone(), *usn),
+                None => registry.insert(spelling, -1),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Fixture;
+
+    #[test]
+    fn split_tags_drops_empty_levels() {
+        assert_eq!(split_tags("a  b::c"), ["a", "b::c"]);
+        assert_eq!(split_tags("::a:: a::::b ::"), ["a", "a::b"]);
+        assert!(split_tags("  ").is_empty());
+    }
+
+    #[test]
+    fn tag_matches_children_and_ignores_case() {
+        assert!(tag_matches("Lang", "lang"));
+        assert!(tag_matches("Lang::English", "lang"));
+        assert!(tag_matches("LANG::English::Verbs", "lang::english"));
+        assert!(!tag_matches("Language", "lang"));
+        assert!(!tag_matches("lang", "lang::english"));
+    }
+
+    fn tags(collection: &Collection, note: i64) -> Vec<String> {
+        collection.note(NoteId(note)).unwrap().tags().to_vec()
+    }
+
+    #[test]
+    fn rename_tag_across_levels_and_case() {
+        let fixture = Fixture::new();
+        fixture
+            .note(1, "A::B::c other", &["a", "b"])
+            .note(2, "a::b a::bc", &["c", "d"])
+            .note(3, "\u{212a}emp::x", &["e", "f"]);
+        let mut collection = fixture.collection();
+
+        assert_eq!(collection.rename_tag("a::b", "x"), Ok(2));
+        assert_eq!(tags(&collection, 1), ["x::c", "other"]);
+        assert_eq!(tags(&collection, 2), ["x", "a::bc"]);
+
+        // The Kelvin sign is three bytes but lowercases to a one byte k
+        assert_eq!(collection.rename_tag("kemp", "temp"), Ok(1));
+        assert_eq!(tags(&collection, 3), ["temp::x"]);
+    }
+
+    #[test]
+    fn rename_tag_merges_tags_it_makes_equal() {
+        let fixture = Fixture::new();
+        fixture.note(1, "a x", &["a", "b"]);
+        let mut collection = fixture.collection();
+
+        assert_eq!(collection.rename_tag("A", "X"), Ok(1));
+        assert_eq!(tags(&collection, 1), ["X"]);
+    }
+
+    #[test]
+    fn rename_tag_needs_single_tags() {
+        let fixture = Fixture::new();
+        fixture.note(1, "a", &["a", "b"]);
+        let mut collection = fixture.collection();
+
+        for (old, new) in [
+            ("a", "x y"),
+            ("a", ""),
+            ("a", " :: "),
+            ("a b", "x"),
+            ("", "x"),
+        ] {
+            assert!(collection.rename_tag(old, new).is_err(), "{} {}", old, new);
+        }
+        assert_eq!(tags(&collection, 1), ["a"]);
+    }
+}
@@ -0,0 +1,149 @@
+/* This file is part of acp.
+ * Copyright (c) 2021 Wyatt Campbell
+ *
+ * See repository LICENSE for information.
+ */
+
+use crate::apkg::Apkg;
+use crate::deck::{now_millis, Collection};
+use rusqlite::{params, Connection};
+use std::fs;
+use std::io::{Cursor, Write};
+use std::path::PathBuf;
+use tempfile::TempDir;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+// Small collections for unit tests, written to a database and loaded the way a package is
+// There is one model, Basic (1000) with Front and Back, and decks Default (1),
+// Default::Sub (2) and Other (3) sharing deck config 1
+
+const SCHEMA: &str = r"
+    CREATE TABLE col (id integer primary key, crt integer not null, mod integer not null, scm integer not null, ver integer not null, dty integer not null, usn integer not null, ls integer not null, conf text not null, models text not null, decks text not null, dconf text not null, tags text not null);
+    CREATE TABLE notes (id integer primary key, guid text not null, mid integer not null, mod integer not null, usn integer not null, tags text not null, flds text not null, sfld integer not null, csum integer not null, flags integer not null, data text not null);
+    CREATE TABLE cards (id integer primary key, nid integer not null, did integer not null, ord integer not null, mod integer not null, usn integer not null, type integer not null, queue integer not null, due integer not null, ivl integer not null, factor integer not null, reps integer not null, lapses integer not null, left integer not null, odue integer not null, odid integer not null, flags integer not null, data text not null);
+    CREATE TABLE revlog (id integer primary key, cid integer not null, usn integer not null, ease integer not null, ivl integer not null, lastIvl integer not null, factor integer not null, time integer not null, type integer not null);
+    CREATE TABLE graves (usn integer not null, oid integer not null, type integer not null);
+";
+
+const CONF: &str = r#"{"curDeck": 1, "activeDecks": [1], "newSpread": 0, "collapseTime": 1200, "timeLim": 0, "estTimes": true, "dueCounts": true, "curModel": 1000, "nextPos": 1, "sortType": "noteFld", "sortBackwards": false, "addToCur": true, "dayLearnFirst": false, "schedVer": 2}"#;
+
+const MODELS: &str = r#"{"1000": {"css": "", "did": 1, "id": 1000, "latexPre": "", "latexPost": "", "mod": 0, "name": "Basic", "sortf": 0, "type": 0, "usn": 0, "tags": [], "vers": [], "req": [[0, "all", [0]]], "tmpls": [{"afmt": "{{Back}}", "bafmt": "", "bqfmt": "", "name": "Card 1", "ord": 0, "qfmt": "{{Front}}", "did": null}], "flds": [{"font": "Arial", "name": "Front", "ord": 0, "rtl": false, "size": 20, "sticky": false, "media": []}, {"font": "Arial", "name": "Back", "ord": 1, "rtl": false, "size": 20, "sticky": false, "media": []}]}}"#;
+
+const DECKS: &str = r#"{
+    "1": {"name": "Default", "extendRev": 50, "usn": 0, "collapsed": false, "browserCollapsed": false, "newToday": [0, 0], "revToday": [0, 0], "lrnToday": [0, 0], "dyn": 0, "extendNew": 10, "conf": 1, "id": 1, "mod": 0, "desc": "", "timeToday": [0, 0]},
+    "2": {"name": "Default::Sub", "extendRev": 50, "usn": 0, "collapsed": false, "browserCollapsed": false, "newToday": [0, 0], "revToday": [0, 0], "lrnToday": [0, 0], "dyn": 0, "extendNew": 10, "conf": 1, "id": 2, "mod": 0, "desc": "", "timeToday": [0, 0]},
+    "3": {"name": "Other", "extendRev": 50, "usn": 0, "collapsed": false, "browserCollapsed": false, "newToday": [0, 0], "revToday": [0, 0], "lrnToday": [0, 0], "dyn": 0, "extendNew": 10, "conf": 1, "id": 3, "mod": 0, "desc": "", "timeToday": [0, 0]}
+}"#;
+
+const DCONF: &str = r#"{"1": {"autoplay": true, "dyn": false, "id": 1, "maxTaken": 60, "mod": 0, "name": "Default", "replayq": true, "timer": 0, "usn": 0, "lapse": {"delays": [10.0], "leechAction": 0, "leechFails": 8, "minInt": 1, "mult": 0.0}, "new": {"bury": true, "delays": [1.0, 10.0], "initialFactor": 2500, "ints": [1, 4, 7], "order": 1, "perDay": 20, "separate": true}, "rev": {"bury": true, "ease4": 1.3, "fuzz": 0.05, "ivlFct": 1.0, "maxIvl": 36500, "perDay": 100}}}"#;
+
+pub(crate) struct Fixture {
+    _dir: TempDir,
+    conn: Connection,
+    path: PathBuf,
+}
+
+impl Fixture {
+    // An empty collection created 100 days ago, at the start of a collection day
+    pub(crate) fn new() -> Self {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("collection.anki2");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(SCHEMA).unwrap();
+
+        let crt = (now_millis() / 1000).div_euclid(86400) * 86400 - 100 * 86400;
+        conn.execute(
+            "INSERT INTO col VALUES (1, ?, 0, 0, 11, 0, 0, 0, ?, ?, ?, ?, '{}')",
+            params![crt, CONF, MODELS, DECKS, DCONF],
+        )
+        .unwrap();
+
+        Fixture {
+            _dir: dir,
+            conn,
+            path,
+        }
+    }
+
+    // Run SQL against the database, for anything the other helpers do not cover
+    pub(crate) fn sql(&self, sql: &str) -> &Self {
+        self.conn.execute_batch(sql).unwrap();
+        self
+    }
+
+    // A Basic note, tags are space separated
+    pub(crate) fn note(&self, id: i64, tags: &str, fields: &[&str]) -> &Self {
+        self.conn
+            .execute(
+                "INSERT INTO notes VALUES (?, ?, 1000, 0, 0, ?, ?, ?, 0, 0, '')",
+                params![
+                    id,
+                    format!("guid{}", id),
+                    format!(" {} ", tags),
+                    fields.join("\x1f"),
+                    fields[0]
+                ],
+            )
+            .unwrap();
+        self
+    }
+
+    // A new card at a position in the new queue
+    pub(crate) fn new_card(&self, id: i64, note: i64, deck: i64, position: i64) -> &Self {
+        self.card(id, note, deck, (0, 0), position, 0)
+    }
+
+    // A review card due on a collection day with an interval in days
+    pub(crate) fn review_card(&self, id: i64, note: i64, deck: i64, due: i64, ivl: i64) -> &Self {
+        self.card(id, note, deck, (2, 2), due, ivl)
+    }
+
+    // State is the type and queue columns
+    fn card(&self, id: i64, note: i64, deck: i64, state: (i64, i64), due: i64, ivl: i64) -> &Self {
+        self.conn
+            .execute(
+                "INSERT INTO cards VALUES (?, ?, ?, 0, 0, 0, ?, ?, ?, ?, 2500, 0, 0, 0, 0, 0, 0, '')",
+                params![id, note, deck, state.0, state.1, due, ivl],
+            )
+            .unwrap();
+        self
+    }
+
+    // A review log entry some seconds ago, type is the revlog type column and ease 0 for none
+    pub(crate) fn revlog(&self, ago: i64, card: i64, ease: i64, kind: i64) -> &Self {
+        self.conn
+            .execute(
+                "INSERT INTO revlog VALUES (?, ?, 0, ?, 1, 1, 2500, 1000, ?)",
+                params![now_millis() - ago * 1000, card, ease, kind],
+            )
+            .unwrap();
+        self
+    }
+
+    pub(crate) fn collection(&self) -> Collection {
+        Collection::new(&self.path).unwrap()
+    }
+
+    // A package of the collection and media files, each file holding its own name
+    pub(crate) fn apkg(&self, media: &[&str]) -> Apkg {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+
+        zip.start_file("collection.anki2", options).unwrap();
+        zip.write_all(&fs::read(&self.path).unwrap()).unwrap();
+
+        let mut names = json::JsonValue::new_object();
+        for (entry, name) in media.iter().enumerate() {
+            names.insert(&entry.to_string(), *name).unwrap();
+            zip.start_file(entry.to_string(), options).unwrap();
+            zip.write_all(name.as_bytes()).unwrap();
+        }
+        zip.start_file("media", options).unwrap();
+        zip.write_all(json::stringify(names).as_bytes()).unwrap();
+
+        let mut reader = zip.finish().unwrap();
+        reader.set_position(0);
+        Apkg::from_reader(reader).unwrap()
+    }
+}